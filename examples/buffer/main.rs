@@ -1,4 +1,4 @@
-/// An example of writing to a buffer and accessing it from JavaScript.
+//! An example of writing to a buffer and accessing it from JavaScript.
 
 const RUST_ARRAY_SIZE: usize = 10;
 static mut RUST_ARRAY: [i32; RUST_ARRAY_SIZE] = [-1; RUST_ARRAY_SIZE];
@@ -6,17 +6,17 @@ static mut RUST_ARRAY: [i32; RUST_ARRAY_SIZE] = [-1; RUST_ARRAY_SIZE];
 #[no_mangle]
 pub extern "C" fn write(i: usize, x: i32) {
     unsafe {
-        for j in 0..10 {
-            RUST_ARRAY[j] = -1;
-        }
+        RUST_ARRAY = [-1; RUST_ARRAY_SIZE];
         // Write `x` at the ith index.
         RUST_ARRAY[i] = x;
     }
 }
 
+/// # Safety
+/// The pointer aliases a `static mut`, so it must not be read while `write` runs.
 #[no_mangle]
 pub unsafe fn expose() -> *const i32 {
-    return &RUST_ARRAY as *const i32;
+    ::std::ptr::addr_of!(RUST_ARRAY) as *const i32
 }
 
 #[no_mangle]
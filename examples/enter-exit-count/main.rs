@@ -1,9 +1,9 @@
-/// An example that returns number of times `entered_func()` and 
-/// `exited_func()` are invoked.
-
-/// We expected `ENTERED_FUNC_COUNT` to be 4 and `EXITED_FUNC_COUNT` to be 2
-/// because `EXITED_FUNC_COUNT` will be pushed onto the call stack before
-/// the last two `exited_func()` are executed.
+//! An example that returns number of times `entered_func()` and
+//! `exited_func()` are invoked.
+//!
+//! We expected `ENTERED_FUNC_COUNT` to be 4 and `EXITED_FUNC_COUNT` to be 2
+//! because `EXITED_FUNC_COUNT` will be pushed onto the call stack before
+//! the last two `exited_func()` are executed.
 
 static mut ENTERED_FUNC_COUNT: usize = 0;
 static mut EXITED_FUNC_COUNT: usize = 0;
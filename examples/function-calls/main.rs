@@ -1,3 +1,7 @@
+// Explicit `return`s are deliberate: they exercise epilogue insertion
+// before `return` instructions as well as before the final `end`.
+#![allow(clippy::needless_return, clippy::neg_multiply)]
+
 #[macro_use]
 extern crate wasm_trace;
 
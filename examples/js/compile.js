@@ -7,9 +7,13 @@
 
 const assert = require('assert');
 const { getNames } = require('./names.js');
+const { createHostImports } = require('./tracer.js');
 
 const createInstance = (module) => {
+    const names = getNames(module);
+    const host = createHostImports(names);
     const config = {
+        ...host.imports,
         memory: new WebAssembly.Memory({
             initial: 256,
         }),
@@ -20,11 +24,12 @@ const createInstance = (module) => {
     };
     return {
         instance: new WebAssembly.Instance(module, config),
-        names: getNames(module),
+        names,
+        flush: host.flush,
     };
 };
 
-const invokeFunction = (func, ...args) => ({ instance, names }) => {
+const invokeFunction = (func, ...args) => ({ instance, names, flush }) => {
     const { exports } = instance;
     assert(exports, 'no exports found');
 
@@ -42,11 +47,9 @@ const invokeFunction = (func, ...args) => ({ instance, names }) => {
         '...',
     );
 
-    return {
-        names,
-        exports,
-        result: exportedFunction(...args),
-    };
+    const result = exportedFunction(...args);
+    flush();
+    return { names, exports, result };
 };
 
 module.exports = { createInstance, invokeFunction };
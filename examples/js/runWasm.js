@@ -20,8 +20,11 @@ const validateArgs = (_, __, wasmFile, funcName, args) => {
 const logResult = ({ result, exports, names }) => {
     console.log('Result of function call:', result);
 
-    // Print the contents of the ring buffer.
-    readBuffer(exports, names);
+    // Print the contents of the ring buffer. Modules instrumented with
+    // `--host-import` have already printed their trace as it happened.
+    if (exports.__log_call) {
+        readBuffer(exports, names);
+    }
 
     return { result, exports, names };
 };
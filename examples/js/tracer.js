@@ -6,60 +6,140 @@ const TRACER = {
     LOG_CALL: '__log_call',
     EXPOSE_TRACER: '__expose_tracer',
     EXPOSE_TRACER_LEN: '__expose_tracer_len',
+    EXPOSE_TRACER_CAPACITY: '__expose_tracer_capacity',
+    EXPOSE_TRACER_HEAD: '__expose_tracer_head',
+    EXPOSE_TRACER_WRAPPED: '__expose_tracer_wrapped',
+    IMPORT_MODULE: 'wasm_trace',
 };
 
 const ENTRY_KIND = {
     FUNCTION_CALL: 0,
     FUNCTION_RETURN_VOID: 1,
-    FUNCTION_RETURN_VALUE: 2,
+    FUNCTION_RETURN_I32: 2,
+    FUNCTION_RETURN_I64: 3,
+    FUNCTION_RETURN_F32: 4,
+    FUNCTION_RETURN_F64: 5,
+    ARGUMENT_I32: 6,
+    ARGUMENT_I64: 7,
+    ARGUMENT_F32: 8,
+    ARGUMENT_F64: 9,
+    TIMESTAMP: 10,
+    CALL_SITE: 11,
+    CALL_SITE_RETURN: 12,
+    INDIRECT_CALL: 13,
 };
 
+const isArgument = kind =>
+    kind >= ENTRY_KIND.ARGUMENT_I32 && kind <= ENTRY_KIND.ARGUMENT_F64;
+
+// Each entry is `[kind, function index, low bits, high bits]`.
+const ENTRY_WORDS = 4;
+
 function getMemory(memory, offset, length = 1) {
     return new Int32Array(memory.buffer, offset, length);
 }
 
-// Print the contents of the tracer buffer, if available.
-function readBuffer(exports, nameMap = new Map()) {
-    const getTracerOffset = exports[TRACER.EXPOSE_TRACER];
-    const getTracerLen = exports[TRACER.EXPOSE_TRACER_LEN];
-    const hasDeps = getTracerOffset && getTracerLen;
+// Reinterpret the logged value bits according to the entry kind.
+function decodeValue(kind, low, high) {
+    const view = new DataView(new ArrayBuffer(8));
+    view.setInt32(0, low, true);
+    view.setInt32(4, high, true);
 
-    if (!hasDeps) {
-        console.error('Could not find tracer dependencies in module.');
-        return;
+    switch (kind) {
+        case ENTRY_KIND.FUNCTION_RETURN_I32:
+        case ENTRY_KIND.ARGUMENT_I32:
+            return [String(low)];
+        case ENTRY_KIND.FUNCTION_RETURN_I64:
+        case ENTRY_KIND.ARGUMENT_I64:
+            return [String(view.getBigInt64(0, true))];
+        case ENTRY_KIND.FUNCTION_RETURN_F32:
+        case ENTRY_KIND.ARGUMENT_F32:
+            return [String(view.getFloat32(0, true))];
+        case ENTRY_KIND.FUNCTION_RETURN_F64:
+        case ENTRY_KIND.ARGUMENT_F64:
+            return [String(view.getFloat64(0, true))];
+        default:
+            return [];
     }
+}
 
-    console.log('\nExecution trace:');
+// Prints trace entries as they are pushed, one `[kind, id, low, high]` entry at a time.
+function createPrinter(nameMap = new Map()) {
+    const stack = [];
+    const indent = () => '  | '.repeat(stack.length);
 
-    const tracer = getTracerOffset();
-    const len = getTracerLen();
+    // A call is printed once all of its argument entries have been read.
+    let pendingCall = null;
+    const printCall = () => {
+        if (!pendingCall) {
+            return;
+        }
+        const { prefix, callee, args } = pendingCall;
+        if (args.length) {
+            console.log(prefix, `call ${callee}(${args.join(', ')})`);
+        } else {
+            console.log(prefix, 'call function', callee);
+        }
+        pendingCall = null;
+    };
 
-    // Read the buffer in two-byte chunks.
-    const callBuffer = getMemory(exports.memory, tracer, len);
-    const chunks = chunk(2, callBuffer);
+    const push = ([kind, id, low, high]) => {
+        // Timestamps are not printed.
+        if (kind === ENTRY_KIND.TIMESTAMP) {
+            return;
+        }
+        if (isArgument(kind)) {
+            if (pendingCall) {
+                pendingCall.args.push(...decodeValue(kind, low, high));
+            }
+            return;
+        }
 
-    const stack = [];
-    const indent = () => '  | '.repeat(stack.length);
+        printCall();
+
+        // Call sites hold the instruction offset in the low bits and the callee
+        // in the high bits, or -1 for `call_indirect`. Only the call is printed,
+        // since the callee logs its own call and return if it is instrumented.
+        if (kind === ENTRY_KIND.CALL_SITE_RETURN) {
+            return;
+        }
+        if (kind === ENTRY_KIND.CALL_SITE) {
+            const caller = nameMap.has(id) ? nameMap.get(id) : id;
+            const callee =
+                high === -1
+                    ? 'indirect'
+                    : nameMap.has(high)
+                        ? nameMap.get(high)
+                        : high;
+            console.log(indent(), `${caller}+${low} calls ${callee}`);
+            return;
+        }
+
+        // Indirect calls hold the instruction offset in the low bits and the
+        // table index in the high bits. Resolving the index to a function needs
+        // the module's element section, which `run` does.
+        if (kind === ENTRY_KIND.INDIRECT_CALL) {
+            const caller = nameMap.has(id) ? nameMap.get(id) : id;
+            console.log(indent(), `${caller}+${low} calls table[${high >>> 0}]`);
+            return;
+        }
 
-    for (const [kind, data] of chunks) {
         if (kind === ENTRY_KIND.FUNCTION_CALL) {
-            const callee = nameMap.has(data)
-                ? nameMap.get(data)
-                : data;
+            const callee = nameMap.has(id)
+                ? nameMap.get(id)
+                : id;
 
-            console.log(indent(), 'call function', callee);
+            pendingCall = { prefix: indent(), callee, args: [] };
 
             // Push the called function onto the local stack.
-            stack.push(data);
+            stack.push(id);
         } else {
-            const callee = stack.pop();
-            const calleeFormat = nameMap.has(callee)
-                ? nameMap.get(callee)
-                : callee;
+            stack.pop();
+            const calleeFormat = nameMap.has(id)
+                ? nameMap.get(id)
+                : id;
 
-            const value = kind === ENTRY_KIND.FUNCTION_RETURN_VALUE
-                ? [data]
-                : [];
+            const value = decodeValue(kind, low, high);
 
             console.log(
                 indent(),
@@ -69,7 +149,58 @@ function readBuffer(exports, nameMap = new Map()) {
                 calleeFormat,
             );
         }
+    };
+
+    return { push, flush: printCall };
+}
+
+// Print the contents of the tracer buffer, if available.
+function readBuffer(exports, nameMap = new Map()) {
+    const getTracerOffset = exports[TRACER.EXPOSE_TRACER];
+    const getTracerLen = exports[TRACER.EXPOSE_TRACER_LEN];
+    const hasDeps = getTracerOffset && getTracerLen;
+
+    if (!hasDeps) {
+        console.error('Could not find tracer dependencies in module.');
+        return;
+    }
+
+    console.log('\nExecution trace:');
+
+    const tracer = getTracerOffset();
+    const len = getTracerLen();
+
+    // Once the buffer wraps, the oldest entry is at the head, and the entries
+    // before it are the newest. Older tracers do not export their head.
+    const getTracerHead = exports[TRACER.EXPOSE_TRACER_HEAD];
+    const getTracerWrapped = exports[TRACER.EXPOSE_TRACER_WRAPPED];
+    const wrapped = getTracerWrapped && getTracerWrapped() !== 0;
+    const head = wrapped && getTracerHead ? getTracerHead() : 0;
+    if (wrapped) {
+        console.log('(buffer wrapped, older entries were dropped)');
     }
+
+    // Read the buffer one entry at a time.
+    const buffer = getMemory(exports.memory, tracer, len);
+    const callBuffer = [...buffer.subarray(head), ...buffer.subarray(0, head)];
+    const printer = createPrinter(nameMap);
+    chunk(ENTRY_WORDS, callBuffer).forEach(printer.push);
+    printer.flush();
+}
+
+// Imports for modules instrumented with `--host-import`, which call
+// `wasm_trace.log(kind, id, bits)` for every entry instead of buffering it,
+// and with `--timestamps host`, which call `wasm_trace.now()` for the time in
+// nanoseconds.
+function createHostImports(nameMap = new Map()) {
+    const printer = createPrinter(nameMap);
+    const log = (kind, id, bits) => {
+        const low = Number(BigInt.asIntN(32, bits));
+        const high = Number(BigInt.asIntN(32, bits >> 32n));
+        printer.push([kind, id, low, high]);
+    };
+    const now = () => process.hrtime.bigint();
+    return { imports: { [TRACER.IMPORT_MODULE]: { log, now } }, flush: printer.flush };
 }
 
-module.exports = { readBuffer, getMemory };
+module.exports = { readBuffer, getMemory, createHostImports };
@@ -0,0 +1,227 @@
+//! Call counting: how many times each instrumented function was called, without
+//! logging the calls themselves.
+//!
+//! `WasmModule::instrument_call_counts` makes each selected function's prologue add
+//! one to a counter of its own, an `i32` or `i64` in a table set aside by
+//! `WasmModule::reserve_memory`. There is no ring buffer and no tracer.
+//! `__call_counts` returns the address of the table and `__call_counts_len` the
+//! number of counters. The width of the counters and the function each one belongs
+//! to are recorded in the `wasm_trace.call_counts` custom section.
+
+use std::io::{self, Write};
+use std::str::FromStr;
+use parity_wasm::elements::*;
+
+use module::WasmModule;
+
+pub static CALL_COUNTS: &str = "__call_counts";
+pub static CALL_COUNTS_LEN: &str = "__call_counts_len";
+
+/// Name of the custom section that lists the counted functions.
+pub static CALL_COUNTS_SECTION: &str = "wasm_trace.call_counts";
+
+/// Width of the longest bar in `write_histogram`.
+const BAR_WIDTH: u64 = 40;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// The type of each counter.
+pub enum CounterWidth {
+    /// Wraps around after 2^32 calls, but takes half the memory.
+    #[default]
+    I32 = 4,
+    I64 = 8,
+}
+
+impl CounterWidth {
+    /// Size of a counter in bytes.
+    pub fn bytes(self) -> u32 {
+        self as u32
+    }
+
+    pub fn from_bytes(bytes: u32) -> Option<Self> {
+        match bytes {
+            4 => Some(CounterWidth::I32),
+            8 => Some(CounterWidth::I64),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for CounterWidth {
+    type Err = String;
+
+    fn from_str(width: &str) -> Result<Self, String> {
+        match width {
+            "i32" => Ok(CounterWidth::I32),
+            "i64" => Ok(CounterWidth::I64),
+            _ => Err(format!("Unknown counter type {}", width)),
+        }
+    }
+}
+
+/// Adds a counter for each of `functions`, in order, to `module`, along with the
+/// functions that expose them and the custom section that lists them, and returns
+/// the address of the first counter.
+pub fn inject(module: &mut WasmModule,
+              functions: &[usize],
+              width: CounterWidth)
+              -> Result<u32, Error> {
+    let mut payload = Vec::with_capacity((functions.len() + 1) * 4);
+    payload.extend_from_slice(&width.bytes().to_le_bytes());
+    for &function in functions {
+        payload.extend_from_slice(&(function as u32).to_le_bytes());
+    }
+    module.inject_counters(functions.len(),
+                           width.bytes(),
+                           (CALL_COUNTS, CALL_COUNTS_LEN),
+                           CALL_COUNTS_SECTION,
+                           payload)
+}
+
+/// Instructions that add one to the counter at `address`.
+pub fn increment(address: u32, width: CounterWidth) -> Vec<Instruction> {
+    match width {
+        CounterWidth::I32 => {
+            vec![Instruction::I32Const(0),
+                 Instruction::I32Const(0),
+                 Instruction::I32Load(2, address),
+                 Instruction::I32Const(1),
+                 Instruction::I32Add,
+                 Instruction::I32Store(2, address)]
+        }
+        CounterWidth::I64 => {
+            vec![Instruction::I32Const(0),
+                 Instruction::I32Const(0),
+                 Instruction::I64Load(3, address),
+                 Instruction::I64Const(1),
+                 Instruction::I64Add,
+                 Instruction::I64Store(3, address)]
+        }
+    }
+}
+
+/// The width of the counters in a module instrumented to count calls, and the
+/// function each counter belongs to, in counter order.
+pub fn counted(module: &WasmModule) -> Result<(CounterWidth, Vec<usize>), Error> {
+    let payload = module.custom_section(CALL_COUNTS_SECTION)
+        .ok_or(Error::Other("The module was not instrumented to count calls"))?;
+    if payload.len() % 4 != 0 {
+        return Err(Error::Other("Invalid call counts section"));
+    }
+    let mut words = payload.chunks(4)
+        .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]));
+    let width = words.next()
+        .and_then(CounterWidth::from_bytes)
+        .ok_or(Error::Other("Invalid call counts section"))?;
+    Ok((width, words.map(|function| function as usize).collect()))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// How many times a function was called.
+pub struct CallCount {
+    /// Index of the function in the function index space.
+    pub id: usize,
+    /// Name of the function, if known.
+    pub name: Option<String>,
+    pub calls: u64,
+}
+
+/// Pairs the `counters` read from a module instrumented to count calls with their
+/// functions, most called first, and in index order among equals.
+pub fn histogram(module: &WasmModule, counters: &[u64]) -> Result<Vec<CallCount>, Error> {
+    let (_, functions) = counted(module)?;
+    if functions.len() != counters.len() {
+        return Err(Error::HeapOther(format!("Expected {} call counters, found {}",
+                                            functions.len(),
+                                            counters.len())));
+    }
+
+    let mut counts = functions.into_iter()
+        .zip(counters)
+        .map(|(id, &calls)| {
+                 CallCount {
+                     id,
+                     name: module.get_function_name(id).map(str::to_owned),
+                     calls,
+                 }
+             })
+        .collect::<Vec<CallCount>>();
+    counts.sort_by(|a, b| b.calls.cmp(&a.calls).then(a.id.cmp(&b.id)));
+    Ok(counts)
+}
+
+/// Writes the number of calls to each function, with a bar scaled to the most
+/// called one, followed by the total.
+pub fn write_histogram<W: Write>(out: &mut W, counts: &[CallCount]) -> io::Result<()> {
+    let most = counts.iter().map(|count| count.calls).max().unwrap_or(0);
+    for count in counts {
+        let name = count.name.clone().unwrap_or_else(|| count.id.to_string());
+        let bar = (u128::from(count.calls) * u128::from(BAR_WIDTH))
+            .checked_div(u128::from(most))
+            .unwrap_or(0) as u64;
+        // Any call at all gets a mark.
+        let bar = if count.calls > 0 { bar.max(1) } else { 0 };
+        writeln!(out,
+                 "{:>10} {:<width$} {}",
+                 count.calls,
+                 "#".repeat(bar as usize),
+                 name,
+                 width = BAR_WIDTH as usize)?;
+    }
+    // 64-bit counters can add up to more than a `u64`.
+    let total = counts.iter().map(|count| u128::from(count.calls)).sum::<u128>();
+    writeln!(out, "total: {} calls", total)
+}
+
+#[cfg(test)]
+mod test_counts {
+    use parity_wasm::builder;
+    use module::WasmModule;
+    use super::{counted, histogram, inject, write_histogram, CallCount, CounterWidth,
+                CALL_COUNTS_SECTION};
+
+    #[test]
+    fn record_functions() {
+        let mut module = WasmModule::from_module(builder::module().build());
+        inject(&mut module, &[3, 5, 7], CounterWidth::I64).unwrap();
+        assert_eq!(counted(&module).unwrap(), (CounterWidth::I64, vec![3, 5, 7]));
+
+        // A truncated section is an error, not a panic.
+        for payload in [vec![8, 0], vec![8, 0, 0, 0, 3], vec![], vec![2, 0, 0, 0]].iter() {
+            let mut module = module.clone();
+            module.set_custom_section(CALL_COUNTS_SECTION, payload.clone());
+            assert!(counted(&module).is_err());
+        }
+
+        assert!(histogram(&module, &[1, 2]).is_err());
+        let counts = histogram(&module, &[2, 0, 8]).unwrap();
+        assert_eq!(counts.iter().map(|count| count.id).collect::<Vec<usize>>(), [7, 3, 5]);
+
+        let mut out = Vec::new();
+        write_histogram(&mut out, &counts).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   format!("{:>10} {:<40} 7\n{:>10} {:<40} 3\n{:>10} {:<40} 5\n{}",
+                           8,
+                           "#".repeat(40),
+                           2,
+                           "#".repeat(10),
+                           0,
+                           "",
+                           "total: 10 calls\n"));
+    }
+
+    #[test]
+    fn histogram_of_large_counts() {
+        let counts = [CallCount { id: 0, name: None, calls: u64::MAX },
+                      CallCount { id: 1, name: None, calls: u64::MAX / 2 }];
+        let mut out = Vec::new();
+        write_histogram(&mut out, &counts).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   format!("{} {} 0\n{} {:<40} 1\ntotal: {} calls\n",
+                           u64::MAX,
+                           "#".repeat(40),
+                           u64::MAX / 2,
+                           "#".repeat(19),
+                           u128::from(u64::MAX) + u128::from(u64::MAX / 2)));
+    }
+}
@@ -0,0 +1,617 @@
+//! Basic-block coverage: counting how many times each block of the instrumented
+//! functions runs, instead of tracing calls.
+//!
+//! `WasmModule::instrument_coverage` gives every function entry, `block`, `loop`,
+//! and `if` or `else` arm a counter of its own. An `if` without an `else` is given
+//! an empty one, so that the path where its condition fails is counted too. The
+//! counters are `i32` words in a region of linear memory set aside by
+//! `WasmModule::reserve_memory`, like the tracer's ring. `__coverage_counters`
+//! returns the address of the first counter and `__coverage_len` the number of
+//! counters. The block each counter belongs to is recorded in the
+//! `wasm_trace.coverage` custom section, so that a report can be made from the
+//! instrumented module alone.
+//!
+//! Besides the text report, the counts can be written as lcov `.info` or Cobertura
+//! XML for CI coverage dashboards. Blocks are keyed by DWARF file and line when the
+//! original module has a `.debug_line` section, and by function otherwise.
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::str::FromStr;
+use parity_wasm::elements::*;
+
+use counts::CounterWidth;
+use dwarf::SourceMap;
+use module::WasmModule;
+
+pub static COVERAGE_COUNTERS: &str = "__coverage_counters";
+pub static COVERAGE_LEN: &str = "__coverage_len";
+
+/// Name of the custom section that lists the counted blocks.
+pub static COVERAGE_SECTION: &str = "wasm_trace.coverage";
+
+/// Each block takes three little-endian `u32`s in the custom section: the function,
+/// the offset and the kind.
+const BLOCK_BYTES: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The instruction that starts a counted block.
+pub enum BlockKind {
+    /// The start of the function body.
+    Entry = 0,
+    Block = 1,
+    Loop = 2,
+    /// The arm of an `if` that runs when the condition holds.
+    If = 3,
+    /// The arm of an `if` that runs otherwise.
+    Else = 4,
+    /// The `else` arm added to an `if` that has none, at the `if`'s `end`.
+    ImplicitElse = 5,
+}
+
+impl BlockKind {
+    pub fn from_u32(kind: u32) -> Option<Self> {
+        let kind = match kind {
+            0 => BlockKind::Entry,
+            1 => BlockKind::Block,
+            2 => BlockKind::Loop,
+            3 => BlockKind::If,
+            4 => BlockKind::Else,
+            5 => BlockKind::ImplicitElse,
+            _ => return None,
+        };
+        Some(kind)
+    }
+
+    /// The kind of block that `inst` starts, if any.
+    pub fn starting(inst: &Instruction) -> Option<Self> {
+        match inst {
+            Instruction::Block(_) => Some(BlockKind::Block),
+            Instruction::Loop(_) => Some(BlockKind::Loop),
+            Instruction::If(_) => Some(BlockKind::If),
+            Instruction::Else => Some(BlockKind::Else),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BlockKind::Entry => "entry",
+            BlockKind::Block => "block",
+            BlockKind::Loop => "loop",
+            BlockKind::If => "if",
+            BlockKind::Else | BlockKind::ImplicitElse => "else",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// A block with a counter.
+pub struct Block {
+    /// Index of the function in the function index space.
+    pub function: usize,
+    /// Index of the instruction that starts the block in the function's body, before
+    /// instrumentation. Function entries are at 0.
+    pub offset: usize,
+    pub kind: BlockKind,
+}
+
+/// Adds the counters for `blocks`, in order, to `module`, along with the functions
+/// that expose them and the custom section that lists the blocks, and returns the
+/// address of the first counter.
+pub fn inject(module: &mut WasmModule, blocks: &[Block]) -> Result<u32, Error> {
+    let mut payload = Vec::with_capacity(blocks.len() * BLOCK_BYTES);
+    for block in blocks {
+        for &word in [block.function as u32, block.offset as u32, block.kind as u32].iter() {
+            payload.extend_from_slice(&word.to_le_bytes());
+        }
+    }
+    module.inject_counters(blocks.len(),
+                           CounterWidth::I32.bytes(),
+                           (COVERAGE_COUNTERS, COVERAGE_LEN),
+                           COVERAGE_SECTION,
+                           payload)
+}
+
+/// The blocks counted in a module instrumented for coverage, in counter order.
+pub fn blocks(module: &WasmModule) -> Result<Vec<Block>, Error> {
+    let payload = module.custom_section(COVERAGE_SECTION)
+        .ok_or(Error::Other("The module was not instrumented for coverage"))?;
+    if payload.len() % BLOCK_BYTES != 0 {
+        return Err(Error::Other("Invalid coverage section"));
+    }
+    payload.chunks(BLOCK_BYTES)
+        .map(|block| {
+            let word = |i: usize| {
+                u32::from_le_bytes([block[i], block[i + 1], block[i + 2], block[i + 3]])
+            };
+            let kind = BlockKind::from_u32(word(8))
+                .ok_or(Error::Other("Invalid block kind in coverage section"))?;
+            Ok(Block {
+                   function: word(0) as usize,
+                   offset: word(4) as usize,
+                   kind,
+               })
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// How many times each block of a function ran.
+pub struct FunctionCoverage {
+    /// Index of the function in the function index space.
+    pub id: usize,
+    /// Name of the function, if known.
+    pub name: Option<String>,
+    /// Each block of the function, in order, with the number of times it ran.
+    pub blocks: Vec<(Block, u32)>,
+}
+
+impl FunctionCoverage {
+    /// Number of blocks that ran at least once.
+    pub fn covered(&self) -> usize {
+        self.blocks.iter().filter(|&&(_, hits)| hits > 0).count()
+    }
+}
+
+/// Pairs the `counters` read from a module instrumented for coverage with their
+/// blocks, grouped by function in index order.
+pub fn report(module: &WasmModule, counters: &[i32]) -> Result<Vec<FunctionCoverage>, Error> {
+    let blocks = blocks(module)?;
+    if blocks.len() != counters.len() {
+        return Err(Error::HeapOther(format!("Expected {} coverage counters, found {}",
+                                            blocks.len(),
+                                            counters.len())));
+    }
+
+    let mut functions: Vec<FunctionCoverage> = Vec::new();
+    for (block, &hits) in blocks.into_iter().zip(counters) {
+        let hits = hits as u32;
+        match functions.last_mut() {
+            Some(function) if function.id == block.function => {
+                function.blocks.push((block, hits));
+            }
+            _ => {
+                functions.push(FunctionCoverage {
+                                   id: block.function,
+                                   name: module.get_function_name(block.function)
+                                       .map(str::to_owned),
+                                   blocks: vec![(block, hits)],
+                               })
+            }
+        }
+    }
+    functions.sort_by_key(|function| function.id);
+    Ok(functions)
+}
+
+/// Writes the number of covered blocks in each function, then the hits of each
+/// block, and finally the total.
+pub fn write_report<W: Write>(out: &mut W, report: &[FunctionCoverage]) -> io::Result<()> {
+    let percent = |covered: usize, total: usize| if total == 0 {
+        100.0
+    } else {
+        covered as f64 * 100.0 / total as f64
+    };
+
+    let (mut covered, mut total) = (0, 0);
+    for function in report {
+        let name = function.name.clone().unwrap_or_else(|| function.id.to_string());
+        writeln!(out,
+                 "{}: {}/{} blocks ({:.1}%)",
+                 name,
+                 function.covered(),
+                 function.blocks.len(),
+                 percent(function.covered(), function.blocks.len()))?;
+        for &(block, hits) in &function.blocks {
+            writeln!(out, "  {:>6} {:<6} {:>10}", block.offset, block.kind.as_str(), hits)?;
+        }
+        covered += function.covered();
+        total += function.blocks.len();
+    }
+    writeln!(out, "total: {}/{} blocks ({:.1}%)", covered, total, percent(covered, total))
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// How `run --coverage` prints the coverage report.
+pub enum CoverageFormat {
+    /// Covered blocks per function, as printed by `write_report`.
+    #[default]
+    Text,
+    /// lcov tracefile (`.info`), for `genhtml` and most coverage services.
+    Lcov,
+    /// Cobertura XML.
+    Cobertura,
+}
+
+impl FromStr for CoverageFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, String> {
+        match format {
+            "text" => Ok(CoverageFormat::Text),
+            "lcov" => Ok(CoverageFormat::Lcov),
+            "cobertura" => Ok(CoverageFormat::Cobertura),
+            _ => Err(format!("Unknown coverage format {}", format)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A function whose entry is in a source file.
+pub struct SourceFunction {
+    /// Index of the function in the function index space.
+    pub id: usize,
+    pub name: String,
+    /// Line of the function's entry, if the source map locates it.
+    pub line: Option<u64>,
+    /// Number of times the function was called.
+    pub hits: u32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// Coverage of the lines of a source file.
+pub struct SourceCoverage {
+    pub path: String,
+    pub functions: Vec<SourceFunction>,
+    /// Each line that starts a block, with the most times any block on it ran.
+    pub lines: BTreeMap<u64, u32>,
+    /// Each block past a function entry, as `(line, function id, index of the block
+    /// in the function, hits)`.
+    pub branches: Vec<(u64, usize, usize, u32)>,
+}
+
+/// Groups `report` by source file. Blocks are placed with `source_map` when it
+/// locates them, and at the entry of their function otherwise. Functions without a
+/// location are listed under `module_path` without a line, and so are their blocks
+/// that the source map does not locate: they count towards the function's coverage,
+/// but not towards any line's.
+pub fn source_coverage(report: &[FunctionCoverage],
+                       module_path: &str,
+                       source_map: Option<&SourceMap>)
+                       -> Vec<SourceCoverage> {
+    let mut files: BTreeMap<String, SourceCoverage> = BTreeMap::new();
+    for function in report {
+        let locate = |offset| source_map.and_then(|map| map.locate(function.id, offset));
+        let entry = locate(0);
+        for (i, &(block, hits)) in function.blocks.iter().enumerate() {
+            let location = match block.kind {
+                BlockKind::Entry => entry.clone(),
+                _ => locate(block.offset).or_else(|| entry.clone()),
+            };
+            let path = location.as_ref().map_or(module_path, |location| &location.file[..]);
+            let file = files.entry(path.to_owned())
+                .or_insert_with(|| {
+                                    SourceCoverage {
+                                        path: path.to_owned(),
+                                        ..SourceCoverage::default()
+                                    }
+                                });
+            let line = location.map(|location| location.line);
+            if let Some(line) = line {
+                let line = file.lines.entry(line).or_insert(0);
+                *line = hits.max(*line);
+            }
+            match (block.kind, line) {
+                (BlockKind::Entry, line) => {
+                    file.functions.push(SourceFunction {
+                                            id: function.id,
+                                            name: function.name
+                                                .clone()
+                                                .unwrap_or_else(|| function.id.to_string()),
+                                            line,
+                                            hits,
+                                        })
+                }
+                (_, Some(line)) => file.branches.push((line, function.id, i, hits)),
+                (_, None) => {}
+            }
+        }
+    }
+    files.into_values().collect()
+}
+
+/// Writes `sources` as an lcov tracefile, with a record per source file. Blocks past
+/// a function's entry are listed as branches, numbered by function and position.
+/// lcov has no way to leave out the line of a function, so functions without one
+/// are put on line 0.
+pub fn write_lcov<W: Write>(out: &mut W, sources: &[SourceCoverage]) -> io::Result<()> {
+    for file in sources {
+        writeln!(out, "TN:")?;
+        writeln!(out, "SF:{}", file.path)?;
+        for function in &file.functions {
+            writeln!(out, "FN:{},{}", function.line.unwrap_or(0), function.name)?;
+        }
+        for function in &file.functions {
+            writeln!(out, "FNDA:{},{}", function.hits, function.name)?;
+        }
+        writeln!(out, "FNF:{}", file.functions.len())?;
+        writeln!(out,
+                 "FNH:{}",
+                 file.functions.iter().filter(|function| function.hits > 0).count())?;
+        for &(line, function, block, hits) in &file.branches {
+            writeln!(out, "BRDA:{},{},{},{}", line, function, block, hits)?;
+        }
+        writeln!(out, "BRF:{}", file.branches.len())?;
+        writeln!(out,
+                 "BRH:{}",
+                 file.branches.iter().filter(|&&(_, _, _, hits)| hits > 0).count())?;
+        for (line, hits) in &file.lines {
+            writeln!(out, "DA:{},{}", line, hits)?;
+        }
+        writeln!(out, "LF:{}", file.lines.len())?;
+        writeln!(out, "LH:{}", file.lines.values().filter(|&&hits| hits > 0).count())?;
+        writeln!(out, "end_of_record")?;
+    }
+    Ok(())
+}
+
+/// Writes `sources` as Cobertura XML: a package for the module, named `module_path`,
+/// with a class per source file and a method per function. As in `write_lcov`,
+/// blocks past a function's entry count as branches.
+pub fn write_cobertura<W: Write>(out: &mut W,
+                                 module_path: &str,
+                                 sources: &[SourceCoverage])
+                                 -> io::Result<()> {
+    fn rate(covered: usize, total: usize) -> f64 {
+        if total == 0 {
+            1.0
+        } else {
+            covered as f64 / total as f64
+        }
+    }
+    let lines = |file: &SourceCoverage| {
+        (file.lines.values().filter(|&&hits| hits > 0).count(), file.lines.len())
+    };
+    let branches = |file: &SourceCoverage| {
+        (file.branches.iter().filter(|&&(_, _, _, hits)| hits > 0).count(), file.branches.len())
+    };
+    let sum = |counts: &dyn Fn(&SourceCoverage) -> (usize, usize)| {
+        sources.iter()
+            .map(counts)
+            .fold((0, 0), |(covered, total), (c, t)| (covered + c, total + t))
+    };
+    let (lines_covered, lines_valid) = sum(&lines);
+    let (branches_covered, branches_valid) = sum(&branches);
+
+    writeln!(out, "<?xml version=\"1.0\" ?>")?;
+    writeln!(out,
+             "<!DOCTYPE coverage SYSTEM \
+              \"http://cobertura.sourceforge.net/xml/coverage-04.dtd\">")?;
+    writeln!(out,
+             "<coverage line-rate=\"{}\" branch-rate=\"{}\" lines-covered=\"{}\" \
+              lines-valid=\"{}\" branches-covered=\"{}\" branches-valid=\"{}\" \
+              complexity=\"0\" version=\"wasm-trace\" timestamp=\"0\">",
+             rate(lines_covered, lines_valid),
+             rate(branches_covered, branches_valid),
+             lines_covered,
+             lines_valid,
+             branches_covered,
+             branches_valid)?;
+    writeln!(out, "  <sources>\n    <source>.</source>\n  </sources>")?;
+    writeln!(out, "  <packages>")?;
+    writeln!(out,
+             "    <package name=\"{}\" line-rate=\"{}\" branch-rate=\"{}\" complexity=\"0\">",
+             xml_escape(module_path),
+             rate(lines_covered, lines_valid),
+             rate(branches_covered, branches_valid))?;
+    writeln!(out, "      <classes>")?;
+    for file in sources {
+        let (lines_covered, lines_valid) = lines(file);
+        let (branches_covered, branches_valid) = branches(file);
+        writeln!(out,
+                 "        <class name=\"{0}\" filename=\"{0}\" line-rate=\"{1}\" \
+                  branch-rate=\"{2}\" complexity=\"0\">",
+                 xml_escape(&file.path),
+                 rate(lines_covered, lines_valid),
+                 rate(branches_covered, branches_valid))?;
+        writeln!(out, "          <methods>")?;
+        for function in &file.functions {
+            let covered = if function.hits > 0 { 1 } else { 0 };
+            let (taken, total) = file.branches
+                .iter()
+                .filter(|branch| branch.1 == function.id)
+                .fold((0, 0), |(taken, total), branch| {
+                    (taken + if branch.3 > 0 { 1 } else { 0 }, total + 1)
+                });
+            writeln!(out,
+                     "            <method name=\"{}\" signature=\"\" line-rate=\"{}\" \
+                      branch-rate=\"{}\" complexity=\"0\">",
+                     xml_escape(&function.name),
+                     rate(covered, 1),
+                     rate(taken, total))?;
+            match function.line {
+                Some(line) => {
+                    writeln!(out,
+                             "              <lines>\n                \
+                              <line number=\"{}\" hits=\"{}\"/>\n              </lines>",
+                             line,
+                             function.hits)?
+                }
+                None => writeln!(out, "              <lines/>")?,
+            }
+            writeln!(out, "            </method>")?;
+        }
+        writeln!(out, "          </methods>")?;
+        writeln!(out, "          <lines>")?;
+        for (&line, &hits) in &file.lines {
+            let (taken, total) = file.branches
+                .iter()
+                .filter(|branch| branch.0 == line)
+                .fold((0, 0), |(taken, total), branch| {
+                    (taken + if branch.3 > 0 { 1 } else { 0 }, total + 1)
+                });
+            if total == 0 {
+                writeln!(out,
+                         "            <line number=\"{}\" hits=\"{}\" branch=\"false\"/>",
+                         line,
+                         hits)?;
+            } else {
+                writeln!(out,
+                         "            <line number=\"{}\" hits=\"{}\" branch=\"true\" \
+                          condition-coverage=\"{}% ({}/{})\"/>",
+                         line,
+                         hits,
+                         taken * 100 / total,
+                         taken,
+                         total)?;
+            }
+        }
+        writeln!(out, "          </lines>")?;
+        writeln!(out, "        </class>")?;
+    }
+    writeln!(out, "      </classes>")?;
+    writeln!(out, "    </package>")?;
+    writeln!(out, "  </packages>")?;
+    writeln!(out, "</coverage>")
+}
+
+fn xml_escape(s: &str) -> String {
+    let mut xml = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => xml.push_str("&amp;"),
+            '<' => xml.push_str("&lt;"),
+            '>' => xml.push_str("&gt;"),
+            '"' => xml.push_str("&quot;"),
+            '\'' => xml.push_str("&apos;"),
+            c => xml.push(c),
+        }
+    }
+    xml
+}
+
+#[cfg(test)]
+mod test_coverage {
+    use parity_wasm::builder;
+    use parity_wasm::elements::{Instruction, Instructions};
+    use module::WasmModule;
+    use super::{blocks, inject, report, source_coverage, write_cobertura, write_lcov,
+                write_report, Block, BlockKind, FunctionCoverage, SourceCoverage,
+                SourceFunction};
+
+    #[test]
+    fn record_blocks() {
+        // The exported `f` (0) and an unnamed function (1), ahead of the functions
+        // that `inject` adds.
+        let function = || {
+            builder::function()
+                .signature().build()
+                .body().with_instructions(Instructions::new(vec![Instruction::End])).build()
+                .build()
+        };
+        let mut module = builder::module();
+        module.push_function(function());
+        module.push_function(function());
+        let module = module.export().field("f").internal().func(0).build().build();
+        let mut module = WasmModule::from_module(module);
+        let counted = [Block { function: 0, offset: 0, kind: BlockKind::Entry },
+                       Block { function: 0, offset: 3, kind: BlockKind::If },
+                       Block { function: 1, offset: 0, kind: BlockKind::Entry }];
+        inject(&mut module, &counted).unwrap();
+        assert_eq!(blocks(&module).unwrap(), counted);
+
+        assert!(report(&module, &[2, 0]).is_err());
+        let functions = report(&module, &[2, 0, 1]).unwrap();
+        assert_eq!(functions.len(), 2);
+        assert_eq!(functions[0].covered(), 1);
+
+        let mut out = Vec::new();
+        write_report(&mut out, &functions).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   concat!("f: 1/2 blocks (50.0%)\n",
+                           "       0 entry           2\n",
+                           "       3 if              0\n",
+                           "1: 1/1 blocks (100.0%)\n",
+                           "       0 entry           1\n",
+                           "total: 2/3 blocks (66.7%)\n"));
+    }
+
+    #[test]
+    fn lcov_and_cobertura() {
+        let block = |function, offset, kind| Block { function, offset, kind };
+        let functions = [FunctionCoverage {
+                             id: 1,
+                             name: Some("f<T>".to_owned()),
+                             blocks: vec![(block(1, 0, BlockKind::Entry), 2),
+                                          (block(1, 3, BlockKind::If), 2),
+                                          (block(1, 5, BlockKind::Else), 0)],
+                         },
+                         FunctionCoverage {
+                             id: 4,
+                             name: None,
+                             blocks: vec![(block(4, 0, BlockKind::Entry), 0)],
+                         }];
+        // Without a source map, only the functions are reported.
+        let sources = source_coverage(&functions, "m.wasm", None);
+        assert_eq!(sources.len(), 1);
+        assert_eq!(sources[0].functions[1].name, "4");
+        assert_eq!(sources[0].functions[1].line, None);
+        assert!(sources[0].lines.is_empty() && sources[0].branches.is_empty());
+
+        let mut out = Vec::new();
+        write_lcov(&mut out, &sources).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   concat!("TN:\n",
+                           "SF:m.wasm\n",
+                           "FN:0,f<T>\n",
+                           "FN:0,4\n",
+                           "FNDA:2,f<T>\n",
+                           "FNDA:0,4\n",
+                           "FNF:2\n",
+                           "FNH:1\n",
+                           "BRF:0\n",
+                           "BRH:0\n",
+                           "LF:0\n",
+                           "LH:0\n",
+                           "end_of_record\n"));
+        let mut out = Vec::new();
+        write_cobertura(&mut out, "m.wasm", &sources).unwrap();
+        let xml = String::from_utf8(out).unwrap();
+        assert!(xml.contains("<lines/>"));
+        assert!(!xml.contains("<line "));
+
+        // `f<T>` starts on line 2, where its `if` and `else` are too, and `4` on line 5.
+        let function = |id: usize, name: &str, line: u64, hits: u32| {
+            SourceFunction { id, name: name.to_owned(), line: Some(line), hits }
+        };
+        let sources = [SourceCoverage {
+                           path: "m.wasm".to_owned(),
+                           functions: vec![function(1, "f<T>", 2, 2), function(4, "4", 5, 0)],
+                           lines: vec![(2, 2), (5, 0)].into_iter().collect(),
+                           branches: vec![(2, 1, 1, 2), (2, 1, 2, 0)],
+                       }];
+        let mut out = Vec::new();
+        write_lcov(&mut out, &sources).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   concat!("TN:\n",
+                           "SF:m.wasm\n",
+                           "FN:2,f<T>\n",
+                           "FN:5,4\n",
+                           "FNDA:2,f<T>\n",
+                           "FNDA:0,4\n",
+                           "FNF:2\n",
+                           "FNH:1\n",
+                           "BRDA:2,1,1,2\n",
+                           "BRDA:2,1,2,0\n",
+                           "BRF:2\n",
+                           "BRH:1\n",
+                           "DA:2,2\n",
+                           "DA:5,0\n",
+                           "LF:2\n",
+                           "LH:1\n",
+                           "end_of_record\n"));
+
+        let mut out = Vec::new();
+        write_cobertura(&mut out, "m.wasm", &sources).unwrap();
+        let xml = String::from_utf8(out).unwrap();
+        assert!(xml.contains("<coverage line-rate=\"0.5\" branch-rate=\"0.5\" \
+                              lines-covered=\"1\" lines-valid=\"2\""));
+        assert!(xml.contains("<method name=\"f&lt;T&gt;\" signature=\"\" line-rate=\"1\" \
+                              branch-rate=\"0.5\""));
+        assert!(xml.contains("<line number=\"2\" hits=\"2\" branch=\"true\" \
+                              condition-coverage=\"50% (1/2)\"/>"));
+        assert!(xml.contains("<line number=\"5\" hits=\"0\" branch=\"false\"/>"));
+        assert!(xml.ends_with("</coverage>\n"));
+    }
+}
@@ -0,0 +1,611 @@
+//! Source locations from the DWARF `.debug_line` section, for reports keyed by
+//! file and line.
+//!
+//! In WebAssembly, DWARF addresses are offsets into the payload of the code section.
+//! Instruction addresses are read from the original bytes of the module, since
+//! instrumenting a module moves its instructions, and parity-wasm does not keep the
+//! original encoding of their immediates.
+
+use std::io::Cursor;
+use parity_wasm::elements::{deserialize_buffer, Deserialize, Error, ImportCountType, Instruction,
+                            Module, VarUint32};
+
+static DEBUG_LINE: &str = ".debug_line";
+static DEBUG_LINE_STR: &str = ".debug_line_str";
+static DEBUG_STR: &str = ".debug_str";
+
+const CUSTOM_SECTION: u8 = 0;
+const CODE_SECTION: u8 = 10;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A line in a source file.
+pub struct Location {
+    pub file: String,
+    pub line: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+/// A row of the line table.
+struct Row {
+    address: u64,
+    /// Index in `SourceMap::files`.
+    file: usize,
+    line: u64,
+    /// Rows past the end of a sequence have no location.
+    end_sequence: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+/// Maps the instructions of a module to the source lines they were compiled from.
+pub struct SourceMap {
+    imports_count: usize,
+    /// Address of each instruction of each function body, in code section order.
+    addresses: Vec<Vec<u64>>,
+    /// Rows of the line table, by address.
+    rows: Vec<Row>,
+    files: Vec<String>,
+}
+
+impl SourceMap {
+    /// Reads the line table of the module in `bytes`, and the address of each of its
+    /// instructions. Returns `None` if the module has no `.debug_line` section.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Option<SourceMap>, Error> {
+        let sections = sections(bytes)?;
+        let custom = |name: &str| {
+            sections.iter()
+                .find(|&&(id, section_name, _)| id == CUSTOM_SECTION && section_name == name)
+                .map(|&(_, _, payload)| payload)
+        };
+        let debug_line = match custom(DEBUG_LINE) {
+            Some(debug_line) => debug_line,
+            None => return Ok(None),
+        };
+        let strings = Strings {
+            debug_str: custom(DEBUG_STR).unwrap_or(&[]),
+            debug_line_str: custom(DEBUG_LINE_STR).unwrap_or(&[]),
+        };
+
+        let mut map = SourceMap {
+            imports_count: deserialize_buffer::<Module>(bytes)?
+                .import_count(ImportCountType::Function),
+            ..SourceMap::default()
+        };
+        if let Some(&(_, _, code)) = sections.iter().find(|&&(id, _, _)| id == CODE_SECTION) {
+            map.addresses = instruction_addresses(code)?;
+        }
+        parse_line_programs(debug_line, &strings, &mut map)
+            .ok_or(Error::Other("Invalid .debug_line section"))?;
+        // Where a sequence ends at the address another one starts, the start wins.
+        map.rows.sort_by_key(|row| (row.address, !row.end_sequence));
+        Ok(Some(map))
+    }
+
+    /// Location of instruction `offset` in the body of function `id`, if the line
+    /// table covers it.
+    pub fn locate(&self, id: usize, offset: usize) -> Option<Location> {
+        let address = *id.checked_sub(self.imports_count)
+            .and_then(|body| self.addresses.get(body))
+            .and_then(|body| body.get(offset))?;
+        let row = match self.rows.binary_search_by_key(&(address, true), |row| {
+            (row.address, !row.end_sequence)
+        }) {
+            Ok(i) => self.rows[i],
+            Err(0) => return None,
+            Err(i) => self.rows[i - 1],
+        };
+        if row.end_sequence {
+            return None;
+        }
+        Some(Location {
+                 file: self.files[row.file].clone(),
+                 line: row.line,
+             })
+    }
+}
+
+/// A section, as `(id, name, payload)`. Only custom sections have a name, and their
+/// payload starts after it.
+type RawSection<'a> = (u8, &'a str, &'a [u8]);
+
+/// Splits a module into its sections.
+fn sections(bytes: &[u8]) -> Result<Vec<RawSection<'_>>, Error> {
+    if bytes.len() < 8 || &bytes[..4] != b"\0asm" {
+        return Err(Error::Other("Not a WebAssembly module"));
+    }
+    let mut reader = Reader::new(&bytes[8..]);
+    let mut sections = Vec::new();
+    while !reader.is_empty() {
+        let section = reader.u8()
+            .and_then(|id| {
+                let size = reader.uleb()? as usize;
+                let payload = reader.bytes(size)?;
+                if id != CUSTOM_SECTION {
+                    return Some((id, "", payload));
+                }
+                let mut payload = Reader::new(payload);
+                let size = payload.uleb()? as usize;
+                let name = ::std::str::from_utf8(payload.bytes(size)?).ok()?;
+                Some((id, name, payload.rest()))
+            })
+            .ok_or(Error::Other("Invalid section"))?;
+        sections.push(section);
+    }
+    Ok(sections)
+}
+
+/// Offset of each instruction of each function body from the start of `code`, the
+/// payload of the code section.
+fn instruction_addresses(code: &[u8]) -> Result<Vec<Vec<u64>>, Error> {
+    let mut cursor = Cursor::new(code);
+    let count = u32::from(VarUint32::deserialize(&mut cursor)?);
+    let mut bodies = Vec::new();
+    for _ in 0..count {
+        let size = u32::from(VarUint32::deserialize(&mut cursor)?) as u64;
+        let end = cursor.position() + size;
+        let locals = u32::from(VarUint32::deserialize(&mut cursor)?);
+        for _ in 0..locals {
+            VarUint32::deserialize(&mut cursor)?;
+            cursor.set_position(cursor.position() + 1);
+        }
+
+        let mut addresses = Vec::new();
+        while cursor.position() < end {
+            addresses.push(cursor.position());
+            Instruction::deserialize(&mut cursor)?;
+        }
+        bodies.push(addresses);
+    }
+    Ok(bodies)
+}
+
+/// String sections that `.debug_line` can refer to.
+struct Strings<'a> {
+    debug_str: &'a [u8],
+    debug_line_str: &'a [u8],
+}
+
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNS_SET_FILE: u8 = 4;
+const DW_LNS_CONST_ADD_PC: u8 = 8;
+const DW_LNS_FIXED_ADVANCE_PC: u8 = 9;
+
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+const DW_LNE_DEFINE_FILE: u8 = 3;
+
+const DW_LNCT_PATH: u64 = 1;
+const DW_LNCT_DIRECTORY_INDEX: u64 = 2;
+
+const DW_FORM_BLOCK: u64 = 0x09;
+const DW_FORM_DATA1: u64 = 0x0b;
+const DW_FORM_DATA2: u64 = 0x05;
+const DW_FORM_DATA4: u64 = 0x06;
+const DW_FORM_DATA8: u64 = 0x07;
+const DW_FORM_DATA16: u64 = 0x1e;
+const DW_FORM_LINE_STRP: u64 = 0x1f;
+const DW_FORM_STRING: u64 = 0x08;
+const DW_FORM_STRP: u64 = 0x0e;
+const DW_FORM_UDATA: u64 = 0x0f;
+
+/// Runs the line number program of every unit in `debug_line`, adding its files and
+/// rows to `map`. Sequences for code that the linker dropped start at address 0 or
+/// at a tombstone past the end of the address space, and are skipped.
+fn parse_line_programs(debug_line: &[u8], strings: &Strings, map: &mut SourceMap) -> Option<()> {
+    let mut units = Reader::new(debug_line);
+    while !units.is_empty() {
+        let (length, offset_size) = match units.u32()? {
+            0xffff_ffff => (units.u64()?, 8),
+            length => (length as u64, 4),
+        };
+        let mut unit = Reader::new(units.bytes(length as usize)?);
+        let version = unit.u16()?;
+        if !(2..=5).contains(&version) {
+            return None;
+        }
+        if version >= 5 {
+            // Address and segment selector sizes.
+            unit.bytes(2)?;
+        }
+        let header_length = unit.offset(offset_size)? as usize;
+        let mut program = unit.clone();
+        program.bytes(header_length)?;
+
+        let min_inst_length = unit.u8()? as u64;
+        if version >= 4 {
+            // Maximum operations per instruction, which is always 1 outside VLIW.
+            unit.u8()?;
+        }
+        unit.u8()?;
+        let line_base = unit.u8()? as i8 as i64;
+        let line_range = unit.u8()?;
+        let opcode_base = unit.u8()?;
+        if line_range == 0 || opcode_base == 0 {
+            return None;
+        }
+        let opcode_lengths = unit.bytes(opcode_base as usize - 1)?;
+
+        // File indices refer into `files`, which refers into `map.files`. Files
+        // defined by the line number program use the same directories.
+        let mut files = Vec::new();
+        let mut directories = Vec::new();
+        if version >= 5 {
+            directories = entries(&mut unit, offset_size, strings)?
+                .into_iter()
+                .map(|(path, _)| path)
+                .collect();
+            for (path, directory) in entries(&mut unit, offset_size, strings)? {
+                let directory = directories.get(directory as usize).map_or("", |dir| &dir[..]);
+                files.push(intern(map, join(directory, &path)));
+            }
+        } else {
+            // Directory 0 and file 0 are the compilation directory and file, which
+            // version 4 and earlier leave out of the header.
+            directories.push(String::new());
+            loop {
+                let directory = unit.cstr()?;
+                if directory.is_empty() {
+                    break;
+                }
+                directories.push(directory.to_owned());
+            }
+            files.push(intern(map, String::new()));
+            loop {
+                let path = unit.cstr()?;
+                if path.is_empty() {
+                    break;
+                }
+                let directory = unit.uleb()? as usize;
+                unit.uleb()?;
+                unit.uleb()?;
+                let directory = directories.get(directory).map_or("", |dir| &dir[..]);
+                files.push(intern(map, join(directory, path)));
+            }
+        }
+
+        // Rows refer to `files` until their sequence ends.
+        let (mut address, mut file, mut line) = (0u64, 1u64, 1u64);
+        let (mut sequence, mut start) = (Vec::new(), None);
+        let row = |sequence: &mut Vec<Row>, address, file: u64, line, end_sequence| {
+            sequence.push(Row { address, file: file as usize, line, end_sequence });
+        };
+        while !program.is_empty() {
+            let opcode = program.u8()?;
+            if opcode >= opcode_base {
+                let adjusted = opcode - opcode_base;
+                address = address.checked_add((adjusted / line_range) as u64 * min_inst_length)?;
+                line = line.checked_add_signed(line_base + (adjusted % line_range) as i64)?;
+                row(&mut sequence, address, file, line, false);
+                continue;
+            }
+            match opcode {
+                0 => {
+                    let length = program.uleb()? as usize;
+                    let mut extended = Reader::new(program.bytes(length)?);
+                    match extended.u8()? {
+                        DW_LNE_END_SEQUENCE => {
+                            row(&mut sequence, address, file, line, true);
+                            let start = start.take().unwrap_or(0);
+                            if start != 0 && start < 0xffff_fffe {
+                                for mut row in sequence.drain(..) {
+                                    row.file = match files.get(row.file) {
+                                        Some(&file) => file,
+                                        None => intern(map, String::new()),
+                                    };
+                                    map.rows.push(row);
+                                }
+                            }
+                            sequence.clear();
+                            address = 0;
+                            file = 1;
+                            line = 1;
+                        }
+                        DW_LNE_SET_ADDRESS => {
+                            address = match length - 1 {
+                                4 => extended.u32()? as u64,
+                                8 => extended.u64()?,
+                                _ => return None,
+                            };
+                            start = start.or(Some(address));
+                        }
+                        DW_LNE_DEFINE_FILE => {
+                            let path = extended.cstr()?;
+                            let directory = extended.uleb()? as usize;
+                            let directory = directories.get(directory).map_or("", |dir| &dir[..]);
+                            let file = intern(map, join(directory, path));
+                            files.push(file);
+                        }
+                        _ => {}
+                    }
+                }
+                DW_LNS_COPY => row(&mut sequence, address, file, line, false),
+                DW_LNS_ADVANCE_PC => {
+                    let advance = program.uleb()?.checked_mul(min_inst_length)?;
+                    address = address.checked_add(advance)?;
+                }
+                DW_LNS_ADVANCE_LINE => line = line.checked_add_signed(program.sleb()?)?,
+                DW_LNS_SET_FILE => file = program.uleb()?,
+                DW_LNS_CONST_ADD_PC => {
+                    let advance = ((255 - opcode_base) / line_range) as u64 * min_inst_length;
+                    address = address.checked_add(advance)?;
+                }
+                DW_LNS_FIXED_ADVANCE_PC => address = address.checked_add(program.u16()? as u64)?,
+                // Column, statement and basic block flags, and the like.
+                _ => {
+                    for _ in 0..opcode_lengths[opcode as usize - 1] {
+                        program.uleb()?;
+                    }
+                }
+            }
+        }
+    }
+    Some(())
+}
+
+/// The value of an entry in a version 5 directory or file name table.
+enum Value {
+    String(String),
+    Unsigned(u64),
+    /// A value that no supported content type has, such as an MD5 checksum.
+    Other,
+}
+
+/// Reads a version 5 directory or file name table, as `(path, directory index)`.
+fn entries(unit: &mut Reader,
+           offset_size: usize,
+           strings: &Strings)
+           -> Option<Vec<(String, u64)>> {
+    let formats = (0..unit.u8()?)
+        .map(|_| Some((unit.uleb()?, unit.uleb()?)))
+        .collect::<Option<Vec<(u64, u64)>>>()?;
+    let count = unit.uleb()?;
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let (mut path, mut directory) = (String::new(), 0);
+        for &(content, form) in &formats {
+            let string = |offset: u64, section: &[u8]| {
+                Reader::new(section.get(offset as usize..)?).cstr().map(str::to_owned)
+            };
+            let value = match form {
+                DW_FORM_STRING => Value::String(unit.cstr()?.to_owned()),
+                DW_FORM_LINE_STRP => {
+                    Value::String(string(unit.offset(offset_size)?, strings.debug_line_str)?)
+                }
+                DW_FORM_STRP => {
+                    Value::String(string(unit.offset(offset_size)?, strings.debug_str)?)
+                }
+                DW_FORM_UDATA => Value::Unsigned(unit.uleb()?),
+                DW_FORM_DATA1 => Value::Unsigned(unit.u8()? as u64),
+                DW_FORM_DATA2 => Value::Unsigned(unit.u16()? as u64),
+                DW_FORM_DATA4 => Value::Unsigned(unit.u32()? as u64),
+                DW_FORM_DATA8 => Value::Unsigned(unit.u64()?),
+                DW_FORM_DATA16 => unit.bytes(16).map(|_| Value::Other)?,
+                DW_FORM_BLOCK => {
+                    let length = unit.uleb()? as usize;
+                    unit.bytes(length).map(|_| Value::Other)?
+                }
+                _ => return None,
+            };
+            match (content, value) {
+                (DW_LNCT_PATH, Value::String(string)) => path = string,
+                (DW_LNCT_DIRECTORY_INDEX, Value::Unsigned(index)) => directory = index,
+                _ => {}
+            }
+        }
+        entries.push((path, directory));
+    }
+    Some(entries)
+}
+
+/// Index of `file` in `map.files`, adding it if needed.
+fn intern(map: &mut SourceMap, file: String) -> usize {
+    match map.files.iter().position(|known| *known == file) {
+        Some(i) => i,
+        None => {
+            map.files.push(file);
+            map.files.len() - 1
+        }
+    }
+}
+
+fn join(directory: &str, path: &str) -> String {
+    if directory.is_empty() || path.starts_with('/') {
+        path.to_owned()
+    } else {
+        format!("{}/{}", directory.trim_end_matches('/'), path)
+    }
+}
+
+#[derive(Clone)]
+/// Reads little-endian values from a DWARF section.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn rest(&self) -> &'a [u8] {
+        self.data
+    }
+
+    fn bytes(&mut self, count: usize) -> Option<&'a [u8]> {
+        if count > self.data.len() {
+            return None;
+        }
+        let (bytes, rest) = self.data.split_at(count);
+        self.data = rest;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes(4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.bytes(8).map(|b| u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+    }
+
+    /// A section offset, which is 8 bytes wide in 64-bit DWARF and 4 otherwise.
+    fn offset(&mut self, size: usize) -> Option<u64> {
+        if size == 8 {
+            self.u64()
+        } else {
+            self.u32().map(u64::from)
+        }
+    }
+
+    fn uleb(&mut self) -> Option<u64> {
+        let (mut value, mut shift) = (0u64, 0);
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= ((byte & 0x7f) as u64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+    }
+
+    fn sleb(&mut self) -> Option<i64> {
+        let (mut value, mut shift) = (0i64, 0);
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= ((byte & 0x7f) as i64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    value |= -1 << shift;
+                }
+                return Some(value);
+            }
+        }
+    }
+
+    /// A NUL-terminated string.
+    fn cstr(&mut self) -> Option<&'a str> {
+        let end = self.data.iter().position(|&byte| byte == 0)?;
+        let string = ::std::str::from_utf8(&self.data[..end]).ok()?;
+        self.data = &self.data[end + 1..];
+        Some(string)
+    }
+}
+
+#[cfg(test)]
+mod test_dwarf {
+    use parity_wasm::{builder, serialize};
+    use parity_wasm::elements::{Instruction, Instructions};
+    use super::{Location, SourceMap};
+
+    /// A function whose `i32.const`, `drop` and `end` are at code section offsets 3,
+    /// 5 and 6, with a version 4 line table that has directory `src` and file
+    /// `src/lib.c`, and runs `program`.
+    fn module_with_program(program: &[u8]) -> Vec<u8> {
+        let code = vec![Instruction::I32Const(1), Instruction::Drop, Instruction::End];
+        let module = builder::module()
+            .function()
+            .signature()
+            .build()
+            .body()
+            .with_instructions(Instructions::new(code))
+            .build()
+            .build()
+            .build();
+        let mut bytes = serialize(module).unwrap();
+
+        let mut header = vec![1, 1, 1, 0xfb, 14, 13, 0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1];
+        header.extend_from_slice(b"src\0\0lib.c\0\x01\0\0\0");
+        let mut unit = vec![4, 0];
+        unit.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        unit.extend_from_slice(&header);
+        unit.extend_from_slice(program);
+
+        let mut section = vec![11];
+        section.extend_from_slice(b".debug_line");
+        section.extend_from_slice(&(unit.len() as u32).to_le_bytes());
+        section.extend_from_slice(&unit);
+        bytes.push(0);
+        bytes.push(section.len() as u8);
+        bytes.extend_from_slice(&section);
+        bytes
+    }
+
+    /// Puts the instructions on lines 10, 12 and 12 of `src/lib.c`, with a sequence
+    /// for dropped code at address 0 that overlaps them.
+    fn module_with_lines() -> Vec<u8> {
+        module_with_program(&[0, 5, 2, 0, 0, 0, 0, 2, 5, 3, 49, 1, 2, 1, 0, 1, 1, // dropped
+                              0, 5, 2, 3, 0, 0, 0, 3, 9, 1, 2, 2, 3, 2, 1, 2, 2, 0, 1, 1])
+    }
+
+    #[test]
+    fn locate_instructions() {
+        let map = SourceMap::from_bytes(&module_with_lines()).unwrap().unwrap();
+        let line = |line| {
+            Some(Location {
+                     file: "src/lib.c".to_owned(),
+                     line,
+                 })
+        };
+        assert_eq!(map.locate(0, 0), line(10));
+        assert_eq!(map.locate(0, 1), line(12));
+        assert_eq!(map.locate(0, 2), line(12));
+        assert_eq!(map.locate(0, 3), None);
+        assert_eq!(map.locate(1, 0), None);
+    }
+
+    #[test]
+    fn define_files() {
+        // `src/def.c` is defined in directory 1, and `bad.c` in a directory that
+        // does not exist.
+        let mut program = vec![0, 5, 2, 3, 0, 0, 0];
+        program.extend_from_slice(b"\0\x0a\x03def.c\0\x01\0\0\x04\x02\x01");
+        program.extend_from_slice(b"\0\x0a\x03bad.c\0\x09\0\0\x04\x03\x02\x02\x01");
+        program.extend_from_slice(&[2, 1, 0, 1, 1]);
+        let map = SourceMap::from_bytes(&module_with_program(&program)).unwrap().unwrap();
+        let location = |file: &str| {
+            Some(Location {
+                     file: file.to_owned(),
+                     line: 1,
+                 })
+        };
+        assert_eq!(map.locate(0, 0), location("src/def.c"));
+        assert_eq!(map.locate(0, 1), location("bad.c"));
+    }
+
+    #[test]
+    fn overflowing_programs() {
+        // Line 1 goes back 5 lines.
+        assert!(SourceMap::from_bytes(&module_with_program(&[3, 0x7b, 1])).is_err());
+        // The address is set to 2^64 - 1, then advanced by 1.
+        let mut program = vec![0, 9, 2];
+        program.extend_from_slice(&[0xff; 8]);
+        program.extend_from_slice(&[2, 1, 1]);
+        assert!(SourceMap::from_bytes(&module_with_program(&program)).is_err());
+    }
+
+    #[test]
+    fn no_debug_info() {
+        let bytes = serialize(builder::module().build()).unwrap();
+        assert!(SourceMap::from_bytes(&bytes).unwrap().is_none());
+        assert!(SourceMap::from_bytes(b"not wasm").is_err());
+    }
+}
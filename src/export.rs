@@ -0,0 +1,354 @@
+//! Writing decoded traces in formats that other tools understand.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::io::{self, Write};
+use std::slice;
+use std::str::FromStr;
+use rustc_demangle::try_demangle;
+
+use options::Clock;
+use trace::{function_times, walk, CallNode, TraceValue, Visit};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+/// Output formats for a decoded trace.
+pub enum TraceFormat {
+    /// Indented calls and returns, as printed by `examples/js/tracer.js`.
+    #[default]
+    Text,
+    /// [Chrome Trace Event Format](https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU)
+    /// JSON, for chrome://tracing or the Perfetto UI.
+    Chrome,
+    /// Brendan Gregg's folded stacks (`a;b;c count`), for `flamegraph.pl` or `inferno`.
+    Folded,
+    /// Calls, inclusive and exclusive time per function.
+    Times,
+}
+
+impl FromStr for TraceFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, String> {
+        match format {
+            "text" => Ok(TraceFormat::Text),
+            "chrome" => Ok(TraceFormat::Chrome),
+            "folded" => Ok(TraceFormat::Folded),
+            "times" => Ok(TraceFormat::Times),
+            _ => Err(format!("Unknown trace format {}", format)),
+        }
+    }
+}
+
+/// Writes call trees as Chrome Trace Event Format JSON, with a begin (`B`) and end
+/// (`E`) event per call.
+/// Timestamps from the host `clock` are in nanoseconds, and converted to the
+/// microseconds Chrome expects. Those of other clocks count events or instructions,
+/// and are written as they are, one microsecond per tick. If the trace has none,
+/// each event is stamped with its position in the trace instead, as if every event
+/// took a microsecond. Calls whose entry or return was not recorded still get both
+/// events, so that the nesting is preserved.
+pub fn write_chrome_trace<W: Write>(out: &mut W,
+                                    roots: &[CallNode],
+                                    clock: Option<Clock>)
+                                    -> io::Result<()> {
+    // Process and thread names label the single track in the viewer.
+    writeln!(out,
+             "{{\"traceEvents\":[\n\
+              {{\"name\":\"process_name\",\"ph\":\"M\",\"pid\":0,\"tid\":0,\
+              \"args\":{{\"name\":\"wasm-trace\"}}}}")?;
+    let mut stamps = Stamps {
+        timed: roots.iter().any(has_timestamps),
+        nanoseconds: clock == Some(Clock::Host),
+        last: 0,
+    };
+    for visit in walk(roots) {
+        let mut args = String::new();
+        let (node, phase, time) = match visit {
+            Visit::Enter(node) => {
+                write!(args, "\"id\":{}", node.id).unwrap();
+                if !node.entered {
+                    args.push_str(",\"entered\":false");
+                }
+                if !node.args.is_empty() {
+                    let values = node.args.iter().map(json_value).collect::<Vec<String>>();
+                    write!(args, ",\"args\":[{}]", values.join(",")).unwrap();
+                }
+                (node, "B", node.start)
+            }
+            Visit::Exit(node) => {
+                if !node.returned {
+                    args.push_str("\"returned\":false");
+                } else if let Some(value) = node.value {
+                    write!(args, "\"return\":{}", json_value(&value)).unwrap();
+                }
+                (node, "E", node.end)
+            }
+        };
+        writeln!(out,
+                 ",{{\"name\":{},\"cat\":\"wasm\",\"ph\":\"{}\",\"ts\":{},\"pid\":0,\
+                  \"tid\":0,\"args\":{{{}}}}}",
+                 json_string(&node_name(node)),
+                 phase,
+                 stamps.next(time),
+                 args)?;
+    }
+    writeln!(out, "]}}")
+}
+
+/// Chrome `ts` values, in microseconds, for events in the order they are written.
+struct Stamps {
+    /// Whether the trace has timestamps at all.
+    timed: bool,
+    /// Whether the timestamps are in nanoseconds, rather than ticks of a clock
+    /// that has no unit.
+    nanoseconds: bool,
+    /// The previous timestamp, or the number of events written if untimed.
+    last: i64,
+}
+
+impl Stamps {
+    /// Stamps the next event, which happened at `time` if it was recorded, and
+    /// otherwise is placed at the time of the previous event.
+    fn next(&mut self, time: Option<i64>) -> String {
+        if self.timed {
+            self.last = time.unwrap_or(self.last);
+            if self.nanoseconds {
+                (self.last as f64 / 1000.0).to_string()
+            } else {
+                self.last.to_string()
+            }
+        } else {
+            self.last += 1;
+            (self.last - 1).to_string()
+        }
+    }
+}
+
+/// Whether any call in the tree was recorded with a timestamp.
+pub fn has_timestamps(node: &CallNode) -> bool {
+    walk(slice::from_ref(node)).any(|visit| match visit {
+        Visit::Enter(node) => node.start.is_some() || node.end.is_some(),
+        Visit::Exit(_) => false,
+    })
+}
+
+/// Writes a table of the number of calls to each function, and the time spent in
+/// it including and excluding the calls it made, slowest first.
+pub fn write_function_times<W: Write>(out: &mut W, roots: &[CallNode]) -> io::Result<()> {
+    writeln!(out, "{:>10} {:>14} {:>14}  function", "calls", "inclusive", "exclusive")?;
+    for time in function_times(roots) {
+        let name = time.name.clone().unwrap_or_else(|| time.id.to_string());
+        writeln!(out,
+                 "{:>10} {:>14} {:>14}  {}",
+                 time.calls,
+                 time.inclusive,
+                 time.exclusive,
+                 name)?;
+    }
+    Ok(())
+}
+
+/// Writes call trees as folded stacks, one line per distinct stack with the total
+/// `weight` of the calls that end in it, sorted by stack.
+/// `weight` gives the cost of a call excluding its children, e.g. 1 to count calls.
+/// Frames are named after the function, demangled if it is a Rust symbol.
+pub fn write_folded_stacks<W, F>(out: &mut W, roots: &[CallNode], weight: F) -> io::Result<()>
+    where W: Write,
+          F: Fn(&CallNode) -> u64
+{
+    let mut stacks = BTreeMap::new();
+    // The stack of each call that is entered and not yet exited, innermost last.
+    let mut open: Vec<String> = Vec::new();
+    for visit in walk(roots) {
+        match visit {
+            Visit::Enter(node) => {
+                let mut stack = open.last().map_or(String::new(), |prefix| format!("{};", prefix));
+                // Frames are separated by `;`, so it cannot appear in a name.
+                stack.push_str(&frame_name(node).replace(';', ":"));
+                open.push(stack);
+            }
+            Visit::Exit(node) => {
+                let stack = open.pop().expect("Exited a call that was entered");
+                *stacks.entry(stack).or_insert(0) += weight(node);
+            }
+        }
+    }
+    for (stack, weight) in stacks {
+        if weight > 0 {
+            writeln!(out, "{} {}", stack, weight)?;
+        }
+    }
+    Ok(())
+}
+
+/// Demangled name of the function, or its index if it has no name.
+fn frame_name(node: &CallNode) -> String {
+    match node.name {
+        Some(ref name) => {
+            try_demangle(name).map_or(name.clone(), |demangled| format!("{:#}", demangled))
+        }
+        None => node.id.to_string(),
+    }
+}
+
+/// Name of the function, or its index if it has none.
+fn node_name(node: &CallNode) -> String {
+    node.name.clone().unwrap_or_else(|| node.id.to_string())
+}
+
+/// Values are written as strings, since JSON numbers cannot hold every `i64`,
+/// infinity or NaN.
+fn json_value(value: &TraceValue) -> String {
+    json_string(&value.to_string())
+}
+
+fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if (c as u32) < 0x20 => write!(json, "\\u{:04x}", c as u32).unwrap(),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[cfg(test)]
+mod test_export {
+    use options::Clock;
+    use trace::{call_tree, function_times, TraceEvent, TraceValue};
+    use super::{json_string, write_chrome_trace, write_folded_stacks, write_function_times};
+
+    #[test]
+    fn chrome_trace() {
+        let events = [TraceEvent::Return { id: 3, name: None, value: None, time: None },
+                      TraceEvent::Call {
+                          id: 0,
+                          name: Some("add".to_owned()),
+                          args: vec![TraceValue::I32(1), TraceValue::F64(0.5)],
+                          time: None,
+                      },
+                      TraceEvent::Return {
+                          id: 0,
+                          name: Some("add".to_owned()),
+                          value: Some(TraceValue::F64(1.5)),
+                          time: None,
+                      },
+                      TraceEvent::Call {
+                          id: 1,
+                          name: Some("loop".to_owned()),
+                          args: vec![],
+                          time: None,
+                      }];
+        let mut out = Vec::new();
+        write_chrome_trace(&mut out, &call_tree(&events), None).unwrap();
+
+        let lines = String::from_utf8(out).unwrap();
+        let lines = lines.lines().collect::<Vec<&str>>();
+        let expected = [
+            r#"{"traceEvents":["#,
+            r#"{"name":"process_name","ph":"M","pid":0,"tid":0,"args":{"name":"wasm-trace"}}"#,
+            r#",{"name":"3","cat":"wasm","ph":"B","ts":0,"pid":0,"tid":0,"args":{"id":3,"entered":false}}"#,
+            r#",{"name":"3","cat":"wasm","ph":"E","ts":1,"pid":0,"tid":0,"args":{}}"#,
+            r#",{"name":"add","cat":"wasm","ph":"B","ts":2,"pid":0,"tid":0,"args":{"id":0,"args":["1","0.5"]}}"#,
+            r#",{"name":"add","cat":"wasm","ph":"E","ts":3,"pid":0,"tid":0,"args":{"return":"1.5"}}"#,
+            r#",{"name":"loop","cat":"wasm","ph":"B","ts":4,"pid":0,"tid":0,"args":{"id":1}}"#,
+            r#",{"name":"loop","cat":"wasm","ph":"E","ts":5,"pid":0,"tid":0,"args":{"returned":false}}"#,
+            "]}",
+        ];
+        assert_eq!(lines, expected);
+    }
+
+    #[test]
+    fn folded_stacks() {
+        let call = |id: usize, name: &str| {
+            TraceEvent::Call { id, name: Some(name.to_owned()), args: vec![], time: None }
+        };
+        let ret = |id: usize| TraceEvent::Return { id, name: None, value: None, time: None };
+        let events = [call(0, "main"),
+                      call(1, "_ZN4core3fmt5write17h5a1a6b9b1c8d6e2fE"),
+                      ret(1),
+                      call(2, "a;b"),
+                      call(1, "_ZN4core3fmt5write17h5a1a6b9b1c8d6e2fE"),
+                      ret(1),
+                      ret(2),
+                      call(1, "_ZN4core3fmt5write17h5a1a6b9b1c8d6e2fE"),
+                      ret(1),
+                      ret(0),
+                      TraceEvent::Call { id: 3, name: None, args: vec![], time: None }];
+        let roots = call_tree(&events);
+
+        let mut out = Vec::new();
+        write_folded_stacks(&mut out, &roots, |_| 1).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "3 1\n\
+                    main 1\n\
+                    main;a:b 1\n\
+                    main;a:b;core::fmt::write 1\n\
+                    main;core::fmt::write 2\n");
+
+        // Only calls with some weight of their own are listed.
+        let mut out = Vec::new();
+        write_folded_stacks(&mut out, &roots, |node| node.children.is_empty() as u64).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "3 1\nmain;a:b;core::fmt::write 1\nmain;core::fmt::write 2\n");
+    }
+
+    #[test]
+    fn timed_chrome_trace() {
+        // Events without a timestamp happen at the time of the previous one.
+        let events = [TraceEvent::Call { id: 0, name: None, args: vec![], time: Some(1500) },
+                      TraceEvent::Call { id: 1, name: None, args: vec![], time: None },
+                      TraceEvent::Return { id: 1, name: None, value: None, time: Some(2000) },
+                      TraceEvent::Return { id: 0, name: None, value: None, time: Some(4250) }];
+        let stamps = |clock| {
+            let mut out = Vec::new();
+            write_chrome_trace(&mut out, &call_tree(&events), Some(clock)).unwrap();
+            let out = String::from_utf8(out).unwrap();
+            out.lines()
+                .filter_map(|line| line.split("\"ts\":").nth(1))
+                .map(|rest| rest.split(',').next().unwrap().to_owned())
+                .collect::<Vec<String>>()
+        };
+        // Host time is in nanoseconds; other clocks tick once a microsecond.
+        assert_eq!(stamps(Clock::Host), ["1.5", "1.5", "2", "4.25"]);
+        assert_eq!(stamps(Clock::Instructions), ["1500", "1500", "2000", "4250"]);
+    }
+
+    #[test]
+    fn deep_call_trees() {
+        // Deep enough to overflow the stack of a test thread if walked recursively.
+        let depth = 100_000;
+        let events = (0..depth)
+            .map(|id| TraceEvent::Call { id, name: None, args: vec![], time: None })
+            .collect::<Vec<TraceEvent>>();
+        let roots = call_tree(&events);
+
+        let mut out = Vec::new();
+        write_chrome_trace(&mut out, &roots, None).unwrap();
+        assert_eq!(out.iter().filter(|&&byte| byte == b'\n').count(), depth * 2 + 3);
+        assert_eq!(function_times(&roots).len(), depth);
+    }
+
+    #[test]
+    fn function_times_table() {
+        let name = Some("f".to_owned());
+        let events = [TraceEvent::Call { id: 0, name, args: vec![], time: Some(0) },
+                      TraceEvent::Return { id: 0, name: None, value: None, time: Some(12) }];
+        let mut out = Vec::new();
+        write_function_times(&mut out, &call_tree(&events)).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "     calls      inclusive      exclusive  function\n\
+                    \x20        1             12             12  f\n");
+    }
+
+    #[test]
+    fn escape_json_strings() {
+        assert_eq!(json_string("a\"b\\c\n\u{1}é"), r#""a\"b\\c\n\u0001é""#);
+    }
+}
@@ -0,0 +1,292 @@
+//! Instruction counting: a measure of the work each function does that, unlike
+//! time, is the same on every run and every host.
+//!
+//! Each basic block of a counted function is charged its static instruction count
+//! when it starts. The count is added to the function's own `i64` accumulator, in
+//! memory set aside by `WasmModule::reserve_memory`, and to a global total.
+//! `__fuel_counters` returns the address of the accumulators and `__fuel_len` their
+//! number, and the `wasm_trace.fuel` custom section lists the function each one
+//! belongs to. As the `instructions` clock, the total timestamps calls and returns,
+//! so that the call tree gives inclusive and exclusive instruction counts.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use parity_wasm::elements::*;
+
+use module::WasmModule;
+
+pub static FUEL_COUNTERS: &str = "__fuel_counters";
+pub static FUEL_LEN: &str = "__fuel_len";
+
+/// Name of the custom section that lists the counted functions.
+pub static FUEL_SECTION: &str = "wasm_trace.fuel";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// The accumulators added to a module by `inject`.
+pub struct Fuel {
+    /// Index of the global that holds the total.
+    pub total: u32,
+    /// Address of the first accumulator.
+    base: u32,
+    /// Index of each counted function's accumulator.
+    accounts: HashMap<usize, u32>,
+}
+
+impl Fuel {
+    /// Whether function `id` has an accumulator.
+    pub fn counts(&self, id: usize) -> bool {
+        self.accounts.contains_key(&id)
+    }
+
+    /// Instructions that add `cost` to the accumulator of function `id` and to the
+    /// total, or none if the function is not counted.
+    pub fn charge(&self, id: usize, cost: u32) -> Vec<Instruction> {
+        let address = match self.accounts.get(&id) {
+            Some(&account) => self.base + account * 8,
+            None => return vec![],
+        };
+        vec![Instruction::I32Const(0),
+             Instruction::I32Const(0),
+             Instruction::I64Load(3, address),
+             Instruction::I64Const(cost as i64),
+             Instruction::I64Add,
+             Instruction::I64Store(3, address),
+             Instruction::GetGlobal(self.total),
+             Instruction::I64Const(cost as i64),
+             Instruction::I64Add,
+             Instruction::SetGlobal(self.total)]
+    }
+
+    /// Charges each basic block of `body`, the body of function `id`, on entry.
+    pub fn charge_body(&self, id: usize, body: &mut FuncBody) {
+        let charges = basic_blocks(body.code().elements());
+        let code = body.code().elements().to_vec();
+        let mut charged = Vec::with_capacity(code.len() + charges.len() * 10);
+        for (offset, inst) in code.into_iter().enumerate() {
+            if let Some(&cost) = charges.get(&offset) {
+                charged.extend(self.charge(id, cost));
+            }
+            charged.push(inst);
+        }
+        *body.code_mut().elements_mut() = charged;
+    }
+}
+
+/// Adds an accumulator for each of `functions`, in order, and the total to `module`,
+/// along with the functions that expose the accumulators and the custom section
+/// that lists them. No function is charged yet.
+pub fn inject(module: &mut WasmModule, functions: &[usize]) -> Result<Fuel, Error> {
+    let mut payload = Vec::with_capacity(functions.len() * 4);
+    for &function in functions {
+        payload.extend_from_slice(&(function as u32).to_le_bytes());
+    }
+    let base = module.inject_counters(functions.len(),
+                                      8,
+                                      (FUEL_COUNTERS, FUEL_LEN),
+                                      FUEL_SECTION,
+                                      payload)?;
+
+    let ty = GlobalType::new(ValueType::I64, true);
+    let init = InitExpr::new(vec![Instruction::I64Const(0), Instruction::End]);
+    let total = module.push_global(GlobalEntry::new(ty, init));
+
+    let accounts = functions.iter()
+        .enumerate()
+        .map(|(account, &id)| (id, account as u32))
+        .collect();
+    Ok(Fuel { total, base, accounts })
+}
+
+/// The basic blocks of `code`, by the offset of their first instruction, with the
+/// number of instructions that run when the block runs to its end.
+/// A block starts at the function entry and after every instruction that control
+/// can flow past from elsewhere: `block`, `loop`, `if`, `else`, `end` and `br_if`.
+/// Instructions after an unconditional branch cannot run until the next `else` or
+/// `end`, and are not counted.
+pub fn basic_blocks(code: &[Instruction]) -> HashMap<usize, u32> {
+    let mut blocks = HashMap::new();
+    let (mut start, mut cost) = (Some(0), 0);
+    for (offset, inst) in code.iter().enumerate() {
+        if start.is_some() {
+            cost += 1;
+        }
+        let next = match inst {
+            Instruction::Block(_) |
+            Instruction::Loop(_) |
+            Instruction::If(_) |
+            Instruction::Else |
+            Instruction::End |
+            Instruction::BrIf(_) => Some(offset + 1),
+            Instruction::Br(_) |
+            Instruction::BrTable(..) |
+            Instruction::Return |
+            Instruction::Unreachable => None,
+            _ => continue,
+        };
+        if let Some(start) = start {
+            blocks.insert(start, cost);
+        }
+        start = next.filter(|&next| next < code.len());
+        cost = 0;
+    }
+    blocks
+}
+
+/// The functions counted in a module instrumented to count instructions, in
+/// accumulator order.
+pub fn counted(module: &WasmModule) -> Result<Vec<usize>, Error> {
+    let payload = module.custom_section(FUEL_SECTION)
+        .ok_or(Error::Other("The module was not instrumented to count instructions"))?;
+    if payload.len() % 4 != 0 {
+        return Err(Error::Other("Invalid instruction count section"));
+    }
+    Ok(payload.chunks(4)
+        .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]) as usize)
+        .collect())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// How many instructions ran in the body of a function, over all its calls.
+pub struct FunctionFuel {
+    /// Index of the function in the function index space.
+    pub id: usize,
+    /// Name of the function, if known.
+    pub name: Option<String>,
+    pub instructions: u64,
+}
+
+/// Pairs the accumulators read from a module instrumented to count instructions
+/// with their functions, most expensive first, and in index order among equals.
+pub fn report(module: &WasmModule, counters: &[u64]) -> Result<Vec<FunctionFuel>, Error> {
+    let functions = counted(module)?;
+    if functions.len() != counters.len() {
+        return Err(Error::HeapOther(format!("Expected {} instruction counters, found {}",
+                                            functions.len(),
+                                            counters.len())));
+    }
+
+    let mut report = functions.into_iter()
+        .zip(counters)
+        .map(|(id, &instructions)| {
+                 FunctionFuel {
+                     id,
+                     name: module.get_function_name(id).map(str::to_owned),
+                     instructions,
+                 }
+             })
+        .collect::<Vec<FunctionFuel>>();
+    report.sort_by(|a, b| b.instructions.cmp(&a.instructions).then(a.id.cmp(&b.id)));
+    Ok(report)
+}
+
+/// Writes the number of instructions that ran in each function, followed by the
+/// total.
+pub fn write_report<W: Write>(out: &mut W, report: &[FunctionFuel]) -> io::Result<()> {
+    writeln!(out, "{:>14}  function", "instructions")?;
+    for function in report {
+        let name = function.name.clone().unwrap_or_else(|| function.id.to_string());
+        writeln!(out, "{:>14}  {}", function.instructions, name)?;
+    }
+    // The accumulators can add up to more than a `u64`.
+    let total = report.iter().map(|function| u128::from(function.instructions)).sum::<u128>();
+    writeln!(out, "{:>14}  total", total)
+}
+
+#[cfg(test)]
+mod test_fuel {
+    use parity_wasm::builder;
+    use parity_wasm::elements::{BlockType, FuncBody, Instruction, Instructions};
+    use module::WasmModule;
+    use options::{Clock, InstrumentOptions};
+    use super::{basic_blocks, counted, write_report, Fuel, FunctionFuel};
+
+    #[test]
+    fn split_basic_blocks() {
+        let code = [Instruction::GetLocal(0),
+                    Instruction::If(BlockType::NoResult),
+                    Instruction::Return,
+                    Instruction::Nop,
+                    Instruction::End,
+                    Instruction::Loop(BlockType::NoResult),
+                    Instruction::GetLocal(0),
+                    Instruction::BrIf(0),
+                    Instruction::End,
+                    Instruction::End];
+        let mut blocks = basic_blocks(&code).into_iter().collect::<Vec<(usize, u32)>>();
+        blocks.sort();
+        // The `nop` after `return` never runs.
+        assert_eq!(blocks, [(0, 2), (2, 1), (5, 1), (6, 2), (8, 1), (9, 1)]);
+    }
+
+    #[test]
+    fn charge_basic_blocks() {
+        use self::Instruction::*;
+
+        let fuel = Fuel {
+            total: 1,
+            base: 16,
+            accounts: vec![(2, 0), (4, 1)].into_iter().collect(),
+        };
+        assert!(fuel.counts(2) && !fuel.counts(3));
+        assert!(fuel.charge(3, 1).is_empty());
+        // Function 4 has the second accumulator.
+        assert_eq!(fuel.charge(4, 5)[2], I64Load(3, 24));
+
+        // Each block is charged its cost before its first instruction.
+        let code = vec![GetLocal(0), If(BlockType::NoResult), Nop, End, End];
+        let mut body = FuncBody::new(vec![], Instructions::new(code));
+        fuel.charge_body(2, &mut body);
+        let expected = [fuel.charge(2, 2),
+                        vec![GetLocal(0), If(BlockType::NoResult)],
+                        fuel.charge(2, 2),
+                        vec![Nop, End],
+                        fuel.charge(2, 1),
+                        vec![End]]
+            .concat();
+        assert_eq!(body.code().elements(), &expected[..]);
+    }
+
+    #[test]
+    fn charge_untraced_callees() {
+        use self::Instruction::*;
+
+        // The exported `caller` (1) calls the internal `callee` (0).
+        let function = |code| {
+            builder::function()
+                .signature().build()
+                .body().with_instructions(Instructions::new(code)).build()
+                .build()
+        };
+        let mut module = builder::module();
+        module.push_function(function(vec![Nop, End]));
+        module.push_function(function(vec![Call(0), End]));
+        let module = module.export().field("caller").internal().func(1).build().build();
+        let mut module = WasmModule::from_module(module);
+
+        let options = InstrumentOptions {
+            timestamps: Some(Clock::Instructions),
+            ..Default::default()
+        };
+        module.instrument_module_with(&options).unwrap();
+
+        // Only `caller` is traced, but `callee` is charged too, so that its
+        // instructions count towards the time of its caller.
+        let functions = counted(&module).unwrap();
+        assert!(functions.contains(&0) && functions.contains(&1));
+        let callee = module.function_bodies()[0].code().elements();
+        assert!(matches!(callee[..3], [I32Const(0), I32Const(0), I64Load(3, _)]));
+        assert!(!callee.iter().any(|inst| matches!(inst, Call(_))));
+    }
+
+    #[test]
+    fn report_large_counts() {
+        let report = [FunctionFuel { id: 0, name: None, instructions: u64::MAX },
+                      FunctionFuel { id: 1, name: Some("f".to_owned()), instructions: 1 }];
+        let mut out = Vec::new();
+        write_report(&mut out, &report).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   format!("  instructions  function\n{}  0\n             1  f\n{}  total\n",
+                           u64::MAX,
+                           u128::from(u64::MAX) + 1));
+    }
+}
@@ -1,12 +1,58 @@
+//! Instrumenting WebAssembly modules to trace function calls, on the host, and
+//! the tracers that record the trace, in the guest.
+//!
+//! Only the guest-side `tracer` module is built without the default `std`
+//! feature, in which case the crate is `no_std`. The `alloc` feature adds the
+//! heap-allocated `Tracer` to it.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(feature = "std")]
 #[macro_use]
 mod macros;
+#[cfg(feature = "std")]
+pub mod counts;
+#[cfg(feature = "std")]
+pub mod coverage;
+#[cfg(feature = "std")]
+pub mod dwarf;
+#[cfg(feature = "std")]
 mod either;
+#[cfg(feature = "std")]
+pub mod export;
+#[cfg(feature = "std")]
+pub mod fuel;
+#[cfg(feature = "std")]
 pub mod module;
+#[cfg(feature = "std")]
+pub mod options;
+#[cfg(feature = "alloc")]
 mod ring_buffer;
+#[cfg(feature = "std")]
+pub mod run;
+#[cfg(feature = "std")]
+pub mod runtime;
+#[cfg(feature = "std")]
+pub mod selection;
+#[cfg(feature = "std")]
+pub mod trace;
 pub mod tracer;
 
+#[cfg(feature = "alloc")]
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate core;
+#[cfg(feature = "std")]
 #[allow(unused_imports)]
 #[macro_use]
 extern crate lazy_static;
+#[cfg(feature = "std")]
 extern crate parity_wasm;
-extern crate itertools;
+#[cfg(feature = "std")]
+extern crate glob;
+#[cfg(feature = "std")]
+extern crate regex;
+#[cfg(feature = "std")]
+extern crate rustc_demangle;
+#[cfg(feature = "std")]
+extern crate wasmi;
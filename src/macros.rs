@@ -1,4 +1,3 @@
-#[macro_use]
 #[allow(unused_macros)]
 macro_rules! map {
     ( $( $k:expr => $v:expr ),+ ) => {{
@@ -1,22 +1,337 @@
 extern crate wasm_trace;
 
 use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::process;
+use std::str::FromStr;
+use wasm_trace::counts::{self, write_histogram, CounterWidth};
+use wasm_trace::coverage::{self, source_coverage, write_cobertura, write_lcov, write_report,
+                           CoverageFormat};
+use wasm_trace::dwarf::SourceMap;
+use wasm_trace::fuel;
+use wasm_trace::export::{has_timestamps, write_chrome_trace, write_folded_stacks,
+                         write_function_times, TraceFormat};
 use wasm_trace::module::WasmModule;
+use wasm_trace::options::{Backend, Clock, InstrumentOptions, Scope};
+use wasm_trace::run::{self, format_value, write_trace, Execution};
+use wasm_trace::selection::Matcher;
+use wasm_trace::trace::{call_tree, decode};
+
+static USAGE: &str = "USAGE: cargo run [OPTIONS] module.wasm
+       cargo run run [OPTIONS] module.wasm FUNCTION [ARG]...
+
+The first form writes the instrumented module to output.wasm. The second
+instruments the module in memory, calls FUNCTION in an embedded interpreter,
+and prints the trace.
+
+OPTIONS:
+    --all-functions     instrument every function, not just exports
+    --include SPEC      only instrument functions matching SPEC, exported or not
+                        (repeatable)
+    --exclude SPEC      never instrument functions matching SPEC (repeatable)
+    --arguments         log argument values on entry
+    --call-sites        also log around every call instruction, including calls
+                        to imported functions
+    --trace-imports     log calls to imported functions through generated wrappers
+    --indirect-calls    log the table index of every call_indirect, to resolve
+                        its target through the element section
+    --coverage          count how many times each block runs instead of tracing;
+                        with run, print a coverage report instead of the trace
+    --count-calls TYPE  count the calls to each function in an i32 or i64 counter
+                        instead of tracing; with run, print a histogram of calls
+    --count-instructions
+                        count the instructions each function runs instead of
+                        tracing; with run, print the count per function
+    --host-import       log through the imported wasm_trace.log
+    --capacity N        keep the last N entries in an injected tracer (default 1024)
+    --timestamps CLOCK  time every call and return with the imported wasm_trace.now
+                        (host), with a counter of timestamps taken (counter) or
+                        with the number of instructions run so far (instructions)
+    --format FORMAT     with run, print the trace as text (default), chrome
+                        (Chrome Trace Event JSON, for chrome://tracing or Perfetto),
+                        folded (folded stacks for flamegraphs, weighted by exclusive
+                        time if timed and by calls otherwise) or times (calls and
+                        inclusive and exclusive time per function); with
+                        --coverage, print the report as text (default), lcov or
+                        cobertura (XML), by DWARF file and line if the module has
+                        a .debug_line section
+
+SPEC is one of:
+    exported, imported      import/export status
+    index:N, index:A..B     function indices (half-open range)
+    regex:RE                regex over raw or demangled Rust names
+    glob:GLOB, GLOB         glob over raw or demangled Rust names
+Only Rust symbols are demangled: match C++ functions by their mangled names.";
 
 fn main() {
-    let path = env::args().nth(1).expect("USAGE: cargo run module.wasm");
-    match WasmModule::from_file(path) {
-        Ok(mut module) => {
-            if let Err(e) = module.instrument_module() {
-                panic!("Error instrumenting module: {}", e);
+    let mut args = env::args().skip(1).peekable();
+    let run = args.peek().is_some_and(|arg| arg == "run");
+    if run {
+        args.next();
+    }
+
+    let mut options = InstrumentOptions::default();
+    let mut format = None;
+    let mut coverage = false;
+    let mut count_calls = None;
+    let mut count_instructions = false;
+    let mut capacity = false;
+    let mut positional = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            // Instrument internal functions as well as exports.
+            "--all-functions" => options.scope = Scope::All,
+            "--include" => {
+                options.selection.include(parse_matcher(args.next()));
+            }
+            "--exclude" => {
+                options.selection.exclude(parse_matcher(args.next()));
             }
-            if let Err(e) = WasmModule::to_file("output.wasm", module) {
-                panic!("Error writing instrumented module: {}", e);
+            "--arguments" => options.log_arguments = true,
+            "--call-sites" => options.call_sites = true,
+            "--trace-imports" => options.trace_imports = true,
+            "--indirect-calls" => options.indirect_calls = true,
+            "--coverage" => coverage = true,
+            "--count-instructions" => count_instructions = true,
+            "--count-calls" => {
+                let width = args.next().expect(USAGE).parse::<CounterWidth>();
+                count_calls = Some(width.unwrap_or_else(|e| panic!("{}\n{}", e, USAGE)));
             }
-            println!("Modified wasm module -> output.wasm");
+            // Send entries to the imported `wasm_trace.log` instead of a buffer.
+            "--host-import" => options.backend = Backend::HostImport,
+            "--capacity" => {
+                capacity = true;
+                options.capacity = args.next()
+                    .expect(USAGE)
+                    .parse()
+                    .unwrap_or_else(|e| panic!("Invalid capacity: {}\n{}", e, USAGE));
+            }
+            "--timestamps" => {
+                options.timestamps = match args.next().expect(USAGE).as_str() {
+                    "host" => Some(Clock::Host),
+                    "counter" => Some(Clock::Counter),
+                    "instructions" => Some(Clock::Instructions),
+                    clock => panic!("Unknown clock {}\n{}", clock, USAGE),
+                };
+            }
+            "--format" => format = Some(args.next().expect(USAGE)),
+            _ if arg.starts_with("--") => panic!("Unknown flag {}\n{}", arg, USAGE),
+            _ => positional.push(arg),
         }
-        Err(e) => {
-            panic!("Error initializing module: {}", e);
+    }
+
+    let mut positional = positional.into_iter();
+    let path = positional.next().expect(USAGE);
+    let mut module = match WasmModule::from_file(&path) {
+        Ok(module) => module,
+        Err(e) => panic!("Error initializing module: {}", e),
+    };
+    let modes = [coverage, count_calls.is_some(), count_instructions];
+    if modes.iter().filter(|&&mode| mode).count() > 1 {
+        panic!("Only one of --coverage, --count-calls and --count-instructions can be used\n{}",
+               USAGE);
+    }
+    if format.is_some() && !run {
+        panic!("--format can only be used with run\n{}", USAGE);
+    }
+    let traced = options.log_arguments || options.call_sites || options.trace_imports ||
+                 options.indirect_calls || options.timestamps.is_some() ||
+                 options.backend != Backend::default() || capacity;
+    if (count_calls.is_some() || count_instructions) && (traced || format.is_some()) {
+        panic!("--arguments, --call-sites, --trace-imports, --indirect-calls, --host-import, \
+                --capacity, --timestamps and --format cannot be used with --count-calls or \
+                --count-instructions\n{}",
+               USAGE);
+    }
+    if coverage && traced {
+        panic!("--arguments, --call-sites, --trace-imports, --indirect-calls, --host-import, \
+                --capacity and --timestamps cannot be used with --coverage\n{}",
+               USAGE);
+    }
+    let instrumented = if coverage {
+        module.instrument_coverage(&options).map(|_| ())
+    } else if let Some(width) = count_calls {
+        module.instrument_call_counts(&options, width).map(|_| ())
+    } else if count_instructions {
+        module.instrument_instruction_counts(&options).map(|_| ())
+    } else {
+        module.instrument_module_with(&options)
+    };
+    if let Err(e) = instrumented {
+        panic!("Error instrumenting module: {}", e);
+    }
+
+    if run {
+        let func = positional.next().expect(USAGE);
+        let args = positional.collect::<Vec<String>>();
+        if coverage {
+            let format = parse_format(format);
+            let bytes = fs::read(&path)
+                .unwrap_or_else(|e| panic!("Error reading module: {}", e));
+            let source_map = SourceMap::from_bytes(&bytes)
+                .unwrap_or_else(|e| panic!("Error reading debug information: {}", e));
+            run_coverage(&module, &func, &args, &path, source_map.as_ref(), format);
+        } else if count_calls.is_some() {
+            run_call_counts(&module, &func, &args);
+        } else if count_instructions {
+            run_instruction_counts(&module, &func, &args);
+        } else {
+            run_function(&module, &func, &args, parse_format(format), options.timestamps);
         }
+        return;
     }
+
+    if let Err(e) = WasmModule::to_file("output.wasm", module) {
+        panic!("Error writing instrumented module: {}", e);
+    }
+    println!("Modified wasm module -> output.wasm");
+}
+
+/// Calls `func` in the instrumented module and prints its result and trace, timed
+/// by `clock` if it is given. Only the trace goes to stdout in formats meant for
+/// other tools. Exits with an error status if the call traps.
+fn run_function(module: &WasmModule,
+                func: &str,
+                args: &[String],
+                format: TraceFormat,
+                clock: Option<Clock>) {
+    run_and_report(module, func, args, format == TraceFormat::Text, |execution, out| {
+        match format {
+            TraceFormat::Text => {
+                writeln!(out, "\nExecution trace:")
+                    .and_then(|()| write_trace(out, &execution.entries, module))
+            }
+            TraceFormat::Chrome => {
+                let events = decode(&execution.entries, module);
+                write_chrome_trace(out, &call_tree(&events), clock)
+            }
+            TraceFormat::Folded => {
+                let roots = call_tree(&decode(&execution.entries, module));
+                if roots.iter().any(has_timestamps) {
+                    write_folded_stacks(out, &roots, |node| {
+                        node.exclusive_time().unwrap_or(0).max(0) as u64
+                    })
+                } else {
+                    write_folded_stacks(out, &roots, |_| 1)
+                }
+            }
+            TraceFormat::Times => {
+                let events = decode(&execution.entries, module);
+                write_function_times(out, &call_tree(&events))
+            }
+        }
+    });
+}
+
+/// Calls `func` in a module instrumented for coverage and prints its result and
+/// the coverage report, by source line if `source_map` is given. Only the report
+/// goes to stdout in formats meant for other tools.
+/// Exits with an error status if the call traps.
+fn run_coverage(module: &WasmModule,
+                func: &str,
+                args: &[String],
+                path: &str,
+                source_map: Option<&SourceMap>,
+                format: CoverageFormat) {
+    run_and_report(module, func, args, format == CoverageFormat::Text, |execution, out| {
+        let report = match coverage::report(module, &execution.counters) {
+            Ok(report) => report,
+            Err(e) => panic!("Error reading coverage: {}", e),
+        };
+        match format {
+            CoverageFormat::Text => {
+                writeln!(out, "\nCoverage:").and_then(|()| write_report(out, &report))
+            }
+            CoverageFormat::Lcov => write_lcov(out, &source_coverage(&report, path, source_map)),
+            CoverageFormat::Cobertura => {
+                write_cobertura(out, path, &source_coverage(&report, path, source_map))
+            }
+        }
+    });
+}
+
+/// Calls `func` in a module instrumented to count calls and prints its result and
+/// the number of calls to each function, most called first. Exits with an error
+/// status if the call traps.
+fn run_call_counts(module: &WasmModule, func: &str, args: &[String]) {
+    run_and_report(module, func, args, true, |execution, out| {
+        let counts = match counts::histogram(module, &execution.call_counts) {
+            Ok(counts) => counts,
+            Err(e) => panic!("Error reading call counts: {}", e),
+        };
+        writeln!(out, "\nCalls:").and_then(|()| write_histogram(out, &counts))
+    });
+}
+
+/// Calls `func` in a module instrumented to count instructions and prints its
+/// result and the number of instructions run in each function, most first. Exits
+/// with an error status if the call traps.
+fn run_instruction_counts(module: &WasmModule, func: &str, args: &[String]) {
+    run_and_report(module, func, args, true, |execution, out| {
+        let report = match fuel::report(module, &execution.instruction_counts) {
+            Ok(report) => report,
+            Err(e) => panic!("Error reading instruction counts: {}", e),
+        };
+        writeln!(out, "\nInstructions:").and_then(|()| fuel::write_report(out, &report))
+    });
+}
+
+/// Calls `func` in the instrumented module, prints its result, to stdout if
+/// `result_to_stdout` and to stderr otherwise, then writes what `report` makes of
+/// the execution to stdout. Exits with an error status if the call traps.
+fn run_and_report<F>(module: &WasmModule,
+                     func: &str,
+                     args: &[String],
+                     result_to_stdout: bool,
+                     report: F)
+    where F: FnOnce(&Execution, &mut io::StdoutLock) -> io::Result<()>
+{
+    let execution = match run::run(module, func, args) {
+        Ok(execution) => execution,
+        Err(e) => panic!("Error running module: {}", e),
+    };
+
+    if execution.wrapped {
+        eprintln!("The tracer buffer wrapped around, so the oldest entries were dropped.");
+    }
+
+    let trapped = match execution.result {
+        Ok(ref results) => {
+            let results = results.iter().map(format_value).collect::<Vec<String>>();
+            let result = format!("Result of function call: {}", results.join(", "));
+            if result_to_stdout {
+                println!("{}", result);
+            } else {
+                eprintln!("{}", result);
+            }
+            false
+        }
+        Err(ref e) => {
+            eprintln!("Function call trapped: {}", e);
+            true
+        }
+    };
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    if let Err(e) = report(&execution, &mut out) {
+        panic!("Error printing report: {}", e);
+    }
+
+    if trapped {
+        process::exit(1);
+    }
+}
+
+/// Parses the `--format` argument, if any, as a trace or coverage format.
+fn parse_format<F: Default + FromStr<Err = String>>(format: Option<String>) -> F {
+    format.map_or_else(F::default,
+                       |format| format.parse().unwrap_or_else(|e| panic!("{}\n{}", e, USAGE)))
+}
+
+fn parse_matcher(spec: Option<String>) -> Matcher {
+    let spec = spec.expect(USAGE);
+    spec.parse()
+        .unwrap_or_else(|e| panic!("{}\n{}", e, USAGE))
 }
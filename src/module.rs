@@ -3,17 +3,35 @@
 use std::path::Path;
 use std::fmt;
 use std::iter;
-use std::{u32, i32};
-use std::collections::HashMap;
+use std::mem;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use parity_wasm::elements::*;
-use itertools::Itertools;
 
+use counts::{self, CounterWidth, CALL_COUNTS, CALL_COUNTS_LEN};
+use coverage::{self, Block, BlockKind, COVERAGE_COUNTERS, COVERAGE_LEN};
+use fuel::{self, basic_blocks, Fuel, FUEL_COUNTERS, FUEL_LEN};
 use either::Either;
-use tracer::{EntryKind, EXPOSE_TRACER, EXPOSE_TRACER_LEN, LOG_CALL};
+use options::{Backend, Clock, InstrumentOptions, Scope};
+use runtime;
+use tracer::{is_tracer_export, EntryKind, CLOCK_IMPORT, LOG_CALL, LOG_IMPORT, LOG_IMPORT_MODULE};
 
-static VOID_VALUE_PLACEHOLDER: i32 = i32::MAX;
+/// Name under which memory is exported, if the module does not export it already.
+pub static MEMORY: &str = "memory";
 
-#[derive(Debug)]
+/// Global exported by linkers for allocators that start at the end of static data.
+static HEAP_BASE: &str = "__heap_base";
+
+/// Size of a page of linear memory, in bytes.
+pub const WASM_PAGE_SIZE: u32 = 65536;
+
+/// Prefix of the names given to the wrappers of imported functions.
+pub static IMPORT_WRAPPER_PREFIX: &str = "__wasm_trace_import_";
+
+/// Name of the custom section that lists the functions that log their calls, each
+/// with the index it logs them under, as pairs of little-endian `u32`s.
+pub static LOGGED_SECTION: &str = "wasm_trace.logged";
+
+#[derive(Debug, Clone)]
 /// Wrapper around the parity-wasm `Module` struct, with convenience functions.
 pub struct WasmModule {
     module: Module,
@@ -23,7 +41,12 @@ pub struct WasmModule {
 impl WasmModule {
     /// Deserializes a `.wasm` file to a module.
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
-        let module = match deserialize_file(path)?.parse_names() {
+        Ok(WasmModule::from_module(deserialize_file(path)?))
+    }
+
+    /// Wraps an already deserialized module.
+    pub fn from_module(module: Module) -> Self {
+        let module = match module.parse_names() {
             Ok(parsed_module) => parsed_module,
             Err((_, unparsed_module)) => unparsed_module,
         };
@@ -32,20 +55,23 @@ impl WasmModule {
             module,
             function_names: HashMap::new(),
         };
+        result.load_function_names();
+        result
+    }
+
+    fn load_function_names(&mut self) {
+        self.function_names.clear();
 
         // Try to populate the names map using the names section.
-        if let Some(NameSection::Function(section)) = result.module.names_section() {
+        if let Some(NameSection::Function(section)) = self.module.names_section() {
             for (function_index, name) in section.names().iter() {
-                result
-                    .function_names
+                self.function_names
                     .insert(function_index as usize, name.to_owned());
             }
         } else {
             // If there's no name section, fall back onto the export names.
-            result.function_names = result.exported_function_names();
+            self.function_names = self.exported_function_names();
         }
-
-        Ok(result)
     }
 
     /// Serializes a module to a file.
@@ -53,6 +79,11 @@ impl WasmModule {
         serialize_to_file(path, wasm_module.module)
     }
 
+    /// Serializes a module to bytes.
+    pub fn into_bytes(self) -> Result<Vec<u8>, Error> {
+        serialize(self.module)
+    }
+
     /// Iterates over the module's imports.
     pub fn imports(&self) -> impl Iterator<Item = &ImportEntry> {
         self.module
@@ -69,7 +100,8 @@ impl WasmModule {
 
     /// Iterates over the imported functions within the function index space of the module.
     /// Imported functions do not have bodies.
-    pub fn imported_functions(&self) -> impl Iterator<Item = WasmFunction> {
+    pub fn imported_functions(&self) -> impl Iterator<Item = WasmFunction<'_>> {
+        let exported = self.exported_function_names();
         self.imports()
             .filter_map(move |import| if let External::Function(tyid) = import.external() {
                      // NOTE: Unlike with Internal::Function(id),
@@ -77,10 +109,9 @@ impl WasmModule {
                      // the type section.
                      let name = import.field();
                      let ty = self.get_type(*tyid)
-                         .expect(format!("Couldn't get type {} for imported function {}",
-                                         tyid,
-                                         name)
-                                         .as_str());
+                         .unwrap_or_else(|| panic!("Couldn't get type {} for imported function {}",
+                                                   tyid,
+                                                   name));
 
                      // `i` is an index into the import section, but not all imports are functions,
                      // so we can't use `i` directly as an index into the function index space.
@@ -91,7 +122,7 @@ impl WasmModule {
                      None
                  })
             .enumerate()
-            .map(|(i, (ty, name))| WasmFunction {
+            .map(move |(i, (ty, name))| WasmFunction {
                  // id is the index in the function index space.
                  // An imported function's id is its order in the import section.
                  id: i,
@@ -99,6 +130,7 @@ impl WasmModule {
                  name: Some(name),
                  body: None,
                  source: SourceSection::Import,
+                 exported: exported.contains_key(&i),
              })
     }
 
@@ -108,7 +140,7 @@ impl WasmModule {
     /// > function, in the order the imports appear in the Import Section,
     /// > if present, followed by an index for each function in the Function Section,
     /// > if present, in the order of that section.
-    pub fn functions(&self) -> impl Iterator<Item = WasmFunction> {
+    pub fn functions(&self) -> impl Iterator<Item = WasmFunction<'_>> {
         let function_count = self.module.functions_space();
         if function_count == 0 {
             return Either::Left(iter::empty::<WasmFunction>());
@@ -118,6 +150,7 @@ impl WasmModule {
         let own_count = self.own_functions_count();
         assert_eq!(function_count, imported_count + own_count);
 
+        let exported = self.exported_function_names();
         let own_functions = self.function_types()
             .zip(self.function_bodies())
             .enumerate()
@@ -132,6 +165,7 @@ impl WasmModule {
                     name,
                     body: Some(body),
                     source: SourceSection::Function,
+                    exported: exported.contains_key(&id),
                 }
             });
 
@@ -141,17 +175,102 @@ impl WasmModule {
 
     /// Instruments a module by adding a prologue and epilogue to each exported function.
     pub fn instrument_module(&mut self) -> Result<(), Error> {
-        let logger = self.exported_function_names()
-            .iter()
-            .find(|(_, name)| *name == LOG_CALL)
-            .map(|(&id, _)| id);
+        self.instrument_module_with(&InstrumentOptions::default())
+    }
 
-        if logger.is_none() {
-            return Err(Error::Other("Could not find tracing functions in module exports"));
-        }
+    /// Instruments a module by adding a prologue and epilogue to each function
+    /// selected by `options`.
+    /// With the buffer backend, if the module does not export the tracing functions
+    /// from `tracer_bootstrap!()`, a tracer runtime is injected into the module first.
+    /// With the host import backend, `wasm_trace.log` is imported if it is not already,
+    /// which shifts the index of every function defined in the module. Functions are
+    /// selected by their indices in the module as it was given, all the same.
+    pub fn instrument_module_with(&mut self, options: &InstrumentOptions) -> Result<(), Error> {
+        let mut selection = self.selected_function_ids(options);
+
+        // Import the clock before looking up the logger, since imports shift the
+        // index of every defined function, including a tracer defined in the module.
+        // The selected functions are shifted along with them.
+        let timestamp = match options.timestamps {
+            None => vec![],
+            Some(Clock::Host) => {
+                let now = match self.imported_function_id(LOG_IMPORT_MODULE, CLOCK_IMPORT) {
+                    Some(id) => id,
+                    None => {
+                        let ty = FunctionType::new(vec![], Some(ValueType::I64));
+                        let id = self.import_function(LOG_IMPORT_MODULE, CLOCK_IMPORT, ty)?;
+                        selection = shift_for_import(selection, id);
+                        id
+                    }
+                };
+                vec![Instruction::Call(now as u32)]
+            }
+            Some(Clock::Counter) => {
+                let ty = GlobalType::new(ValueType::I64, true);
+                let init = InitExpr::new(vec![Instruction::I64Const(0), Instruction::End]);
+                let counter = self.push_global(GlobalEntry::new(ty, init));
+                vec![Instruction::GetGlobal(counter),
+                     Instruction::I64Const(1),
+                     Instruction::I64Add,
+                     Instruction::SetGlobal(counter),
+                     Instruction::GetGlobal(counter)]
+            }
+            // Read once the module's own functions are known, below.
+            Some(Clock::Instructions) => vec![],
+        };
+
+        let logger = match options.backend {
+            Backend::Buffer => {
+                let logger = self.exported_function_names()
+                    .iter()
+                    .find(|(_, name)| *name == LOG_CALL)
+                    .map(|(&id, _)| id);
+                match logger {
+                    Some(id) => id,
+                    None => runtime::inject(self, options.capacity)?,
+                }
+            }
+            Backend::HostImport => {
+                match self.imported_function_id(LOG_IMPORT_MODULE, LOG_IMPORT) {
+                    Some(id) => id,
+                    None => {
+                        let ty = FunctionType::new(vec![ValueType::I32,
+                                                        ValueType::I32,
+                                                        ValueType::I64],
+                                                   None);
+                        let id = self.import_function(LOG_IMPORT_MODULE, LOG_IMPORT, ty)?;
+                        selection = shift_for_import(selection, id);
+                        id
+                    }
+                }
+            }
+        };
+
+        // Count the instructions of every function but the tracer's, traced or not,
+        // so that untraced callees count towards the time of their callers. Any
+        // imports are in place by now, so the counted indices are final.
+        let fuel = match options.timestamps {
+            Some(Clock::Instructions) => {
+                let excluded = self.tracer_function_ids();
+                let functions = (self.imported_functions_count()..self.functions().count())
+                    .filter(|id| !excluded.contains(id))
+                    .collect::<Vec<usize>>();
+                Some(fuel::inject(self, &functions)?)
+            }
+            _ => None,
+        };
+        let timestamp = match fuel {
+            Some(ref fuel) => vec![Instruction::GetGlobal(fuel.total)],
+            None => timestamp,
+        };
 
         let mut working = CodeSection::with_bodies(self.function_bodies().to_vec());
-        self.add_tracing_instructions(logger.unwrap(), &mut working)?;
+        let traced = self.add_tracing_instructions(logger,
+                                                   &timestamp,
+                                                   &selection,
+                                                   options,
+                                                   fuel.as_ref(),
+                                                   &mut working)?;
 
         // Replace the module code section with the instrumented bodies.
         if let Some(current_section) = self.module.code_section_mut() {
@@ -160,112 +279,940 @@ impl WasmModule {
             return Err(Error::Other("Could not replace code section with instrumented version"));
         }
 
-        return Ok(());
+        let mut logged = traced.into_iter().map(|id| (id, id)).collect::<Vec<(usize, usize)>>();
+        if options.trace_imports {
+            logged.extend(self.wrap_imports(logger, &timestamp, options));
+        }
+        let mut payload = Vec::with_capacity(logged.len() * 8);
+        for (function, logged_id) in logged {
+            payload.extend_from_slice(&(function as u32).to_le_bytes());
+            payload.extend_from_slice(&(logged_id as u32).to_le_bytes());
+        }
+        self.set_custom_section(LOGGED_SECTION, payload);
+
+        Ok(())
     }
 
+    /// Instruments the functions selected by `options` for basic-block coverage, instead
+    /// of tracing them: each function entry, `block`, `loop`, and `if` or `else` arm
+    /// increments a counter of its own (see `coverage`). An `if` without an `else` gets
+    /// one that only increments its counter. Returns the counted blocks, in counter
+    /// order.
+    /// Only `scope` and `selection` are taken from `options`. The tracer, if any, is
+    /// not instrumented.
+    pub fn instrument_coverage(&mut self,
+                               options: &InstrumentOptions)
+                               -> Result<Vec<Block>, Error> {
+        let excluded = self.tracer_function_ids();
+        let mut blocks = Vec::new();
+        for func in self.functions().skip(self.imported_functions_count()) {
+            if excluded.contains(&func.id) || !selected(&func, options) {
+                continue;
+            }
+            blocks.push(Block { function: func.id, offset: 0, kind: BlockKind::Entry });
+            // Whether each enclosing block is an `if` that has no `else` yet.
+            let mut open = Vec::new();
+            for (offset, inst) in func.instructions().enumerate() {
+                if let Some(kind) = BlockKind::starting(inst) {
+                    blocks.push(Block { function: func.id, offset, kind });
+                }
+                match inst {
+                    Instruction::Block(_) | Instruction::Loop(_) => open.push(false),
+                    Instruction::If(_) => open.push(true),
+                    Instruction::Else => {
+                        if let Some(without_else) = open.last_mut() {
+                            *without_else = false;
+                        }
+                    }
+                    Instruction::End => {
+                        let without_else = open.pop().unwrap_or(false);
+                        if without_else {
+                            let kind = BlockKind::ImplicitElse;
+                            blocks.push(Block { function: func.id, offset, kind });
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        // The counters come after every existing function, so no indices change.
+        let base = coverage::inject(self, &blocks)?;
+        let imports_count = self.imported_functions_count();
+        let bodies = match self.module.code_section_mut() {
+            Some(section) => section.bodies_mut(),
+            None => return Ok(blocks),
+        };
+        let mut counters = blocks.iter().enumerate().peekable();
+        while let Some((counter, entry)) = counters.next() {
+            let address = |counter: usize| base + counter as u32 * 4;
+            let body = &mut bodies[entry.function - imports_count];
+            let code = body.code().elements().to_vec();
+            let mut instrumented = counts::increment(address(counter), CounterWidth::I32);
+            for (offset, inst) in code.into_iter().enumerate() {
+                let starts_block = counters.peek().is_some_and(|&(_, block)| {
+                    block.function == entry.function && block.offset == offset &&
+                    block.kind != BlockKind::Entry
+                });
+                if !starts_block {
+                    instrumented.push(inst);
+                    continue;
+                }
+                let (counter, block) = counters.next().expect("Peeked at the next block");
+                if block.kind == BlockKind::ImplicitElse {
+                    // The `if` has no result, since it has no `else`, so the new arm
+                    // can be empty but for its counter.
+                    instrumented.push(Instruction::Else);
+                    instrumented.extend(counts::increment(address(counter), CounterWidth::I32));
+                    instrumented.push(inst);
+                } else {
+                    instrumented.push(inst);
+                    instrumented.extend(counts::increment(address(counter), CounterWidth::I32));
+                }
+            }
+            *body.code_mut().elements_mut() = instrumented;
+        }
+
+        Ok(blocks)
+    }
+
+    /// Instruments the functions selected by `options` to count their calls, instead
+    /// of tracing them: each prologue increments a counter of the given `width` (see
+    /// `counts`). Returns the counted functions, in counter order.
+    /// Only `scope` and `selection` are taken from `options`. The tracer, if any, is
+    /// not instrumented.
+    pub fn instrument_call_counts(&mut self,
+                                  options: &InstrumentOptions,
+                                  width: CounterWidth)
+                                  -> Result<Vec<usize>, Error> {
+        let excluded = self.tracer_function_ids();
+        let functions = self.functions()
+            .skip(self.imported_functions_count())
+            .filter(|func| !excluded.contains(&func.id) && selected(func, options))
+            .map(|func| func.id)
+            .collect::<Vec<usize>>();
+
+        // The counters come after every existing function, so no indices change.
+        let base = counts::inject(self, &functions, width)?;
+        let imports_count = self.imported_functions_count();
+        if let Some(section) = self.module.code_section_mut() {
+            let bodies = section.bodies_mut();
+            for (counter, &id) in functions.iter().enumerate() {
+                let address = base + counter as u32 * width.bytes();
+                let code = bodies[id - imports_count].code_mut().elements_mut();
+                code.splice(0..0, counts::increment(address, width));
+            }
+        }
+
+        Ok(functions)
+    }
+
+    /// Instruments the functions selected by `options` to count the instructions they
+    /// run, instead of tracing them: each basic block adds its instruction count to
+    /// an accumulator for its function (see `fuel`). Returns the counted functions,
+    /// in accumulator order.
+    /// Only `scope` and `selection` are taken from `options`. The tracer, if any, is
+    /// not instrumented. To attribute counts to calls as well, trace the module with
+    /// the `Clock::Instructions` clock instead.
+    pub fn instrument_instruction_counts(&mut self,
+                                         options: &InstrumentOptions)
+                                         -> Result<Vec<usize>, Error> {
+        let excluded = self.tracer_function_ids();
+        let functions = self.functions()
+            .skip(self.imported_functions_count())
+            .filter(|func| !excluded.contains(&func.id) && selected(func, options))
+            .map(|func| func.id)
+            .collect::<Vec<usize>>();
+
+        let fuel = fuel::inject(self, &functions)?;
+        let imports_count = self.imported_functions_count();
+        if let Some(section) = self.module.code_section_mut() {
+            let bodies = section.bodies_mut();
+            for &id in &functions {
+                fuel.charge_body(id, &mut bodies[id - imports_count]);
+            }
+        }
+
+        Ok(functions)
+    }
+
+    /// Adds a wrapper for each selected imported function, which logs the call and
+    /// return under the import's own index and forwards to the import in between.
+    /// Calls to the import and table elements referring to it then go through the
+    /// wrapper, except from the tracer. Imports from `wasm_trace` are never wrapped,
+    /// and re-exported imports are left as they are.
+    /// Returns each wrapper with the import it wraps.
+    fn wrap_imports(&mut self,
+                    logger_id: usize,
+                    timestamp: &[Instruction],
+                    options: &InstrumentOptions)
+                    -> Vec<(usize, usize)> {
+        let excluded = self.tracer_function_ids();
+        let wrapped = self.wrapped_import_ids(options);
+        let imports = self.imports()
+            .filter(|import| matches!(import.external(), External::Function(_)))
+            .zip(self.imported_functions())
+            .filter(|(_, func)| wrapped.contains(&func.id))
+            .map(|(import, func)| {
+                let Type::Function(ty) = func.ty;
+                (func.id, ty.clone(), import.field().to_owned())
+            })
+            .collect::<Vec<(usize, FunctionType, String)>>();
+
+        // The wrapper's only call is the import itself, which is already logged.
+        let wrapper_options = InstrumentOptions {
+            call_sites: false,
+            ..options.clone()
+        };
+        // Ordered by import, so that the logged section is the same on every run.
+        let mut wrappers = BTreeMap::new();
+        for (import, ty, field) in imports {
+            let mut code = (0..ty.params().len() as u32)
+                .map(Instruction::GetLocal)
+                .collect::<Vec<Instruction>>();
+            code.push(Instruction::Call(import as u32));
+            code.push(Instruction::End);
+            let mut body = FuncBody::new(vec![], Instructions::new(code));
+            self.instrument_function(logger_id,
+                                     timestamp,
+                                     &excluded,
+                                     import,
+                                     &ty,
+                                     &wrapper_options,
+                                     None,
+                                     &mut body);
+            let name = format!("{}{}", IMPORT_WRAPPER_PREFIX, field);
+            let wrapper = self.push_function(ty, body, Some(&name));
+            wrappers.insert(import as u32, wrapper as u32);
+        }
+
+        let wrapper_ids = wrappers.values().map(|&w| w as usize).collect::<HashSet<usize>>();
+        let imports_count = self.imported_functions_count();
+        let redirect = |id: &mut u32| if let Some(&wrapper) = wrappers.get(id) {
+            *id = wrapper;
+        };
+        for section in self.module.sections_mut() {
+            match section {
+                Section::Code(section) => {
+                    for (i, body) in section.bodies_mut().iter_mut().enumerate() {
+                        let id = i + imports_count;
+                        if excluded.contains(&id) || wrapper_ids.contains(&id) {
+                            continue;
+                        }
+                        for inst in body.code_mut().elements_mut() {
+                            if let Instruction::Call(callee) = inst {
+                                redirect(callee);
+                            }
+                        }
+                    }
+                }
+                Section::Element(section) => {
+                    for segment in section.entries_mut() {
+                        segment.members_mut().iter_mut().for_each(redirect);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        wrappers.into_iter()
+            .map(|(import, wrapper)| (wrapper as usize, import as usize))
+            .collect()
+    }
+
+    /// Indices of the functions with a body that `options` selects for tracing.
+    fn selected_function_ids(&self, options: &InstrumentOptions) -> HashSet<usize> {
+        self.functions()
+            .skip(self.imported_functions_count())
+            .filter(|func| selected(func, options))
+            .map(|func| func.id)
+            .collect()
+    }
+
+    /// Indices of the imported functions that `wrap_imports` wraps: those selected by
+    /// `options`, except imports from `wasm_trace`.
+    fn wrapped_import_ids(&self, options: &InstrumentOptions) -> HashSet<usize> {
+        self.imports()
+            .filter(|import| matches!(import.external(), External::Function(_)))
+            .zip(self.imported_functions())
+            .filter(|(import, func)| {
+                import.module() != LOG_IMPORT_MODULE && options.selection.matches(func)
+            })
+            .map(|(_, func)| func.id)
+            .collect()
+    }
+
+    /// Instruments the functions in `selection`, except the tracer's.
+    /// `timestamp` pushes the current time as an `i64`, or is empty to log no time.
+    /// Every function with an accumulator in `fuel` is charged for its instructions,
+    /// whether it is traced or not. Returns the traced functions.
     fn add_tracing_instructions(&self,
                                 logger_id: usize,
+                                timestamp: &[Instruction],
+                                selection: &HashSet<usize>,
+                                options: &InstrumentOptions,
+                                fuel: Option<&Fuel>,
                                 working: &mut CodeSection)
-                                -> Result<(), Error> {
+                                -> Result<Vec<usize>, Error> {
         let imports_count = self.imported_functions_count();
-        let to_instrument = working
+        let excluded = self.tracer_function_ids();
+        // Calls to imports that get a wrapper are logged by the wrapper, so logging
+        // them at the call site as well would log each call twice.
+        let mut unlogged = excluded.clone();
+        if options.trace_imports {
+            unlogged.extend(self.wrapped_import_ids(options));
+        }
+        let bodies = working
             .bodies_mut()
             .iter_mut()
             .zip(self.functions().skip(imports_count))
-            .enumerate()
-            .filter_map(|(i, (mut_body, func))| {
-                let id = i + imports_count;
-                // Only instrument exported functions for now.
-                match self.exported_function_names().get(&id) {
-                    None => None,
-                    Some(name) if name == EXPOSE_TRACER || name == EXPOSE_TRACER_LEN ||
-                                  name == LOG_CALL => None,
-                    _ => {
-                        let return_ty = match func.ty {
-                            Type::Function(ty) => ty.return_type(),
-                        };
-                        Some((id, return_ty, mut_body))
+            .enumerate();
+
+        let mut traced_ids = Vec::new();
+        for (i, (mut_body, func)) in bodies {
+            let id = i + imports_count;
+            // Never instrument the tracer itself, or it would recurse into itself.
+            let traced = !excluded.contains(&id) && selection.contains(&id);
+            if traced {
+                let Type::Function(ty) = func.ty;
+                self.instrument_function(logger_id,
+                                         timestamp,
+                                         &unlogged,
+                                         id,
+                                         ty,
+                                         options,
+                                         fuel,
+                                         mut_body);
+                traced_ids.push(id);
+            } else if let Some(fuel) = fuel.filter(|fuel| fuel.counts(id)) {
+                fuel.charge_body(id, mut_body);
+            }
+        }
+
+        Ok(traced_ids)
+    }
+
+    /// Indices of the tracer's own exports (`__log_call`, `__expose_tracer`,
+    /// `__expose_tracer_len`) and of the functions that expose injected counters,
+    /// along with every function they call, transitively.
+    /// Calls through `call_indirect` cannot be resolved statically and are not followed.
+    fn tracer_function_ids(&self) -> HashSet<usize> {
+        let counter_exports = [COVERAGE_COUNTERS,
+                               COVERAGE_LEN,
+                               CALL_COUNTS,
+                               CALL_COUNTS_LEN,
+                               FUEL_COUNTERS,
+                               FUEL_LEN];
+        let mut pending = self.exported_function_names()
+            .into_iter()
+            .filter(|(_, name)| is_tracer_export(name) || counter_exports.contains(&&name[..]))
+            .map(|(id, _)| id)
+            .collect::<Vec<usize>>();
+
+        let imports_count = self.imported_functions_count();
+        let bodies = self.function_bodies();
+        let mut reachable = HashSet::new();
+
+        while let Some(id) = pending.pop() {
+            if !reachable.insert(id) || id < imports_count {
+                continue;
+            }
+            if let Some(body) = bodies.get(id - imports_count) {
+                for inst in body.code().elements() {
+                    if let Instruction::Call(callee) = inst {
+                        pending.push(*callee as usize);
                     }
                 }
-            });
-
-        for (id, return_ty, mut_body) in to_instrument {
-            self.instrument_function(logger_id, id, return_ty, mut_body);
+            }
         }
 
-        Ok(())
+        reachable
     }
 
+    /// Calls to functions in `unlogged`, such as the tracer's own, are not logged as
+    /// call sites.
+    /// If `fuel` has an accumulator for the function, each of its basic blocks is
+    /// charged on entry, after the prologue.
+    #[allow(clippy::too_many_arguments)]
     fn instrument_function(&self,
                            logger_id: usize,
+                           timestamp: &[Instruction],
+                           unlogged: &HashSet<usize>,
                            id: usize,
-                           return_ty: Option<ValueType>,
+                           ty: &FunctionType,
+                           options: &InstrumentOptions,
+                           fuel: Option<&Fuel>,
                            mut_body: &mut FuncBody) {
         let call_logger = Instruction::Call(logger_id as u32);
 
         // Record that a function call occurred, and the id of the callee.
-        let prologue = vec![Instruction::I32Const(EntryKind::FunctionCall as i32),
-                            Instruction::I32Const(id as i32),
-                            call_logger.clone()];
-
-        // Record returning from the function.
-        let mut epilogue = match return_ty {
-            // If the function has a return type, we need to capture the returned value from
-            // the top of the stack.
-            Some(ty) => {
-                // Create a new local to store the return value.
-                let return_local = Local::new(1, ty);
-                let return_local_id: u32 = mut_body.locals().iter().map(|loc| loc.count()).sum();
-                mut_body.locals_mut().push(return_local);
-
-                // Capture the top of the stack into our local and return that.
-                vec![Instruction::TeeLocal(return_local_id),
-                     Instruction::I32Const(EntryKind::FunctionReturnValue as i32),
-                     Instruction::GetLocal(return_local_id),
-                     call_logger.clone()]
+        let mut prologue = vec![Instruction::I32Const(EntryKind::FunctionCall as i32),
+                                Instruction::I32Const(id as i32),
+                                Instruction::I64Const(0),
+                                call_logger.clone()];
+
+        // Follow the call with one entry per argument, in order.
+        if options.log_arguments {
+            for (param, &param_ty) in ty.params().iter().enumerate() {
+                prologue.extend(vec![Instruction::I32Const(argument_kind(param_ty) as i32),
+                                     Instruction::I32Const(id as i32),
+                                     Instruction::GetLocal(param as u32)]);
+                prologue.extend(value_bits(param_ty));
+                prologue.push(call_logger.clone());
+            }
+        }
+
+        // Record the time of the call or return in an entry of its own.
+        let log_timestamp = if timestamp.is_empty() {
+            vec![]
+        } else {
+            let mut log = vec![Instruction::I32Const(EntryKind::Timestamp as i32),
+                               Instruction::I32Const(id as i32)];
+            log.extend(timestamp.iter().cloned());
+            log.push(call_logger.clone());
+            log
+        };
+        prologue.extend(log_timestamp.iter().cloned());
+
+        // Locals are indexed after the parameters.
+        let mut next_local = ty.params().len() as u32 +
+                             mut_body.locals().iter().map(|loc| loc.count()).sum::<u32>();
+
+        // If the function has a return type, create a new local to store the return value.
+        let return_local = ty.return_type().map(|return_ty| {
+            mut_body.locals_mut().push(Local::new(1, return_ty));
+            next_local += 1;
+            next_local - 1
+        });
+
+        // Record returning from the function, assuming the return value (if any)
+        // has already been stored in `return_local`.
+        let mut log_return = match (ty.return_type(), return_local) {
+            (Some(return_ty), Some(local)) => {
+                let mut log = vec![Instruction::I32Const(return_kind(return_ty) as i32),
+                                   Instruction::I32Const(id as i32),
+                                   Instruction::GetLocal(local)];
+                log.extend(value_bits(return_ty));
+                log.push(call_logger.clone());
+                log
             }
             // If the function has no return value, we simply record that the return
-            // is void, and use a placeholder value for the data.
-            None => {
+            // is void, with no value bits.
+            _ => {
                 vec![Instruction::I32Const(EntryKind::FunctionReturnVoid as i32),
-                     Instruction::I32Const(VOID_VALUE_PLACEHOLDER),
+                     Instruction::I32Const(id as i32),
+                     Instruction::I64Const(0),
                      call_logger.clone()]
             }
         };
+        log_return.extend(log_timestamp.iter().cloned());
+
+        // Record returning from the function, capturing the returned value from
+        // the top of the stack.
+        let epilogue = return_local
+            .map(Instruction::TeeLocal)
+            .into_iter()
+            .chain(log_return.iter().cloned())
+            .collect::<Vec<Instruction>>();
+
+        let code = mut_body.code().elements().to_vec();
+        let charges = match fuel {
+            Some(fuel) if fuel.counts(id) => basic_blocks(&code),
+            _ => HashMap::new(),
+        };
 
-        let mut instrumented = prologue;
+        // Scratch local for `br_if` conditions and `br_table` indices, created on first use.
+        let mut scratch_local = None;
+        let mut scratch = || {
+            *scratch_local.get_or_insert_with(|| {
+                mut_body.locals_mut().push(Local::new(1, ValueType::I32));
+                next_local += 1;
+                next_local - 1
+            })
+        };
 
-        // Iterate over all instructions, using a moving window to check if the
-        // next instruction is `return`.
-        // If so, append the epilogue onto the instrumented body, along with
-        // the current instruction.
-        for (curr, next) in mut_body.code().elements().into_iter().tuple_windows() {
-            instrumented.push(curr.clone());
-            if let Instruction::Return = next {
-                instrumented.append(&mut epilogue.clone());
-            }
-        }
-
-        // Since we iterated over tuple windows but only pushed the first element of
-        // each pair, we missed the last instruction, which is always `end` according
-        // to the spec.
-        // Since `end` implicitly returns, we want to add the epilogue there as well.
-        match instrumented.last() {
-            // Is the end reachable? If not, there will be nothing on the stack,
-            // so `tee_local` will throw an error.
-            Some(Instruction::Unreachable) => {}
-            Some(_) => {
-                instrumented.append(&mut epilogue);
-            }
-            _ => {}
+        // Record a call made at instruction `offset` to `callee`, or through a table,
+        // just before or after it happens. The operand stack is left untouched.
+        let log_call_site = |kind: EntryKind, offset: usize, callee: Option<u32>| {
+            let callee = callee.unwrap_or(u32::MAX);
+            let mut log = vec![Instruction::I32Const(kind as i32),
+                               Instruction::I32Const(id as i32),
+                               Instruction::I64Const(((callee as i64) << 32) | offset as i64),
+                               call_logger.clone()];
+            log.extend(log_timestamp.iter().cloned());
+            log
         };
 
-        // Add the final instruction.
-        instrumented.push(Instruction::End);
+        let mut instrumented = prologue;
+
+        // Number of blocks enclosing the current instruction. A branch to label `depth`
+        // targets the function body itself, so it returns from the function.
+        let mut depth = 0;
+
+        for (offset, inst) in code.into_iter().enumerate() {
+            if let (Some(fuel), Some(&cost)) = (fuel, charges.get(&offset)) {
+                instrumented.extend(fuel.charge(id, cost));
+            }
+
+            // Record the table slot that a `call_indirect` calls through, which is on
+            // top of the stack, before any call site entry.
+            if let Instruction::CallIndirect(..) = inst {
+                if options.indirect_calls {
+                    let index = scratch();
+                    instrumented.extend(vec![Instruction::TeeLocal(index),
+                                             Instruction::I32Const(EntryKind::IndirectCall as i32),
+                                             Instruction::I32Const(id as i32),
+                                             Instruction::GetLocal(index),
+                                             Instruction::I64ExtendUI32,
+                                             Instruction::I64Const(32),
+                                             Instruction::I64Shl,
+                                             Instruction::I64Const(offset as i64),
+                                             Instruction::I64Or,
+                                             call_logger.clone()]);
+                }
+            }
+
+            let callee = match inst {
+                Instruction::Call(callee) => Some(Some(callee)),
+                Instruction::CallIndirect(..) => Some(None),
+                _ => None,
+            };
+            match callee {
+                Some(Some(callee)) if callee as usize == logger_id ||
+                                      unlogged.contains(&(callee as usize)) => {}
+                Some(callee) if options.call_sites => {
+                    instrumented.extend(log_call_site(EntryKind::CallSite, offset, callee));
+                    instrumented.push(inst);
+                    instrumented.extend(log_call_site(EntryKind::CallSiteReturn, offset, callee));
+                    continue;
+                }
+                _ => {}
+            }
+
+            match inst {
+                Instruction::Block(_) | Instruction::Loop(_) | Instruction::If(_) => depth += 1,
+                Instruction::End if depth > 0 => depth -= 1,
+                // The final `end` implicitly returns.
+                Instruction::End => {
+                    match instrumented.last() {
+                        // Is the end reachable? If not, there will be nothing on the stack,
+                        // so `tee_local` will throw an error.
+                        Some(Instruction::Unreachable) => {}
+                        _ => instrumented.extend(epilogue.iter().cloned()),
+                    }
+                }
+                Instruction::Return => instrumented.extend(epilogue.iter().cloned()),
+                Instruction::Br(label) if label == depth => {
+                    instrumented.extend(epilogue.iter().cloned())
+                }
+                Instruction::BrIf(label) if label == depth => {
+                    // Only log if the branch is taken, in which case we return directly.
+                    // Blocks cannot see the enclosing operand stack, so the return value
+                    // is passed in through `return_local`.
+                    if let Some(local) = return_local {
+                        let condition = scratch();
+                        instrumented.extend(vec![Instruction::SetLocal(condition),
+                                                 Instruction::TeeLocal(local),
+                                                 Instruction::GetLocal(condition)]);
+                    }
+                    instrumented.push(Instruction::If(BlockType::NoResult));
+                    instrumented.extend(log_return.iter().cloned());
+                    instrumented.extend(return_local.map(Instruction::GetLocal));
+                    instrumented.push(Instruction::Return);
+                    instrumented.push(Instruction::End);
+                    continue;
+                }
+                Instruction::BrTable(ref targets, default)
+                    if default == depth || targets.contains(&depth) => {
+                    // Wrap the branch in a new block, and redirect branches that would
+                    // leave the function to the end of that block, where we log the return.
+                    // Every other target is now one block further out.
+                    let retarget = |label: u32| if label == depth { 0 } else { label + 1 };
+                    let targets = targets.iter().cloned().map(retarget).collect::<Vec<u32>>();
+                    let block_ty = ty.return_type().map_or(BlockType::NoResult, BlockType::Value);
+
+                    let index = scratch();
+                    instrumented.push(Instruction::SetLocal(index));
+                    instrumented.extend(return_local.map(Instruction::SetLocal));
+                    instrumented.push(Instruction::Block(block_ty));
+                    instrumented.extend(return_local.map(Instruction::GetLocal));
+                    instrumented.push(Instruction::GetLocal(index));
+                    instrumented.push(Instruction::BrTable(targets.into_boxed_slice(),
+                                                           retarget(default)));
+                    instrumented.push(Instruction::End);
+                    instrumented.extend(epilogue.iter().cloned());
+                    instrumented.push(Instruction::Return);
+                    continue;
+                }
+                _ => {}
+            }
+            instrumented.push(inst);
+        }
 
         // Update the working copy of the function body with the new instructions.
-        let ref mut insts = mut_body.code_mut().elements_mut();
-        **insts = instrumented;
+        *mut_body.code_mut().elements_mut() = instrumented;
+    }
+
+    /// Adds a function type to the type section, unless an identical one exists,
+    /// and returns its index in the type index space.
+    pub fn push_type(&mut self, ty: FunctionType) -> u32 {
+        let ty = Type::Function(ty);
+        if let Some(tyid) = self.types().iter().position(|t| *t == ty) {
+            return tyid as u32;
+        }
+        let types = self.section_mut(Section::Type(TypeSection::default()), |section| {
+            match section {
+                Section::Type(section) => Some(section.types_mut()),
+                _ => None,
+            }
+        });
+        types.push(ty);
+        types.len() as u32 - 1
+    }
+
+    /// Appends a function to the module, and returns its index in the function index space.
+    /// Since it comes after every existing function, no other indices change.
+    pub fn push_function(&mut self, ty: FunctionType, body: FuncBody, name: Option<&str>) -> usize {
+        let id = self.module.functions_space();
+        self.splice_function(id, ty, body, name);
+        id
+    }
+
+    /// Inserts a function defined by the module at index `id` in the function index space.
+    /// Functions from `id` onwards move up by one, and every reference to them is
+    /// renumbered (see `remap_functions`).
+    /// `id` must lie between the last imported function and the end of the index space.
+    /// `body` is taken as is, so its calls should already use the new indices.
+    pub fn insert_function(&mut self,
+                           id: usize,
+                           ty: FunctionType,
+                           body: FuncBody,
+                           name: Option<&str>)
+                           -> Result<(), Error> {
+        if id < self.imported_functions_count() || id > self.module.functions_space() {
+            return Err(Error::HeapOther(format!("Cannot insert a defined function at index {}",
+                                                id)));
+        }
+        self.remap_functions(|old| if old as usize >= id { old + 1 } else { old })?;
+        self.splice_function(id, ty, body, name);
+        Ok(())
+    }
+
+    /// Index in the function index space of the function imported as `module.field`.
+    pub fn imported_function_id(&self, module: &str, field: &str) -> Option<usize> {
+        self.imports()
+            .filter(|import| matches!(import.external(), External::Function(_)))
+            .position(|import| import.module() == module && import.field() == field)
+    }
+
+    /// Adds a function import after the existing imported functions, and returns its
+    /// index in the function index space. Every function defined in the module moves
+    /// up by one, and every reference to them is renumbered (see `remap_functions`).
+    pub fn import_function(&mut self,
+                           module: &str,
+                           field: &str,
+                           ty: FunctionType)
+                           -> Result<usize, Error> {
+        let id = self.imported_functions_count();
+        self.remap_functions(|old| shifted_for_import(old as usize, id) as u32)?;
+        let tyid = self.push_type(ty);
+
+        // Imports of other kinds do not take up function indices, so appending
+        // to the import section gives the new function index `id`.
+        self.section_mut(Section::Import(ImportSection::default()), |section| {
+                match section {
+                    Section::Import(section) => Some(section.entries_mut()),
+                    _ => None,
+                }
+            })
+            .push(ImportEntry::new(module.to_owned(), field.to_owned(), External::Function(tyid)));
+        self.name_function(id, field);
+        Ok(id)
+    }
+
+    /// Places a defined function at `id` without renumbering anything.
+    fn splice_function(&mut self, id: usize, ty: FunctionType, body: FuncBody, name: Option<&str>) {
+        let position = id - self.imported_functions_count();
+        let tyid = self.push_type(ty);
+        self.section_mut(Section::Function(FunctionSection::default()), |section| {
+                match section {
+                    Section::Function(section) => Some(section.entries_mut()),
+                    _ => None,
+                }
+            })
+            .insert(position, Func::new(tyid));
+        self.section_mut(Section::Code(CodeSection::default()), |section| {
+                match section {
+                    Section::Code(section) => Some(section.bodies_mut()),
+                    _ => None,
+                }
+            })
+            .insert(position, body);
+
+        match name {
+            Some(name) => self.name_function(id, name),
+            None => self.load_function_names(),
+        }
+    }
+
+    /// Records `name` for function `id` in the name section, if the module has one.
+    fn name_function(&mut self, id: usize, name: &str) {
+        if let Some(NameSection::Function(section)) = self.module.names_section_mut() {
+            section.names_mut().insert(id as u32, name.to_owned());
+        }
+        self.load_function_names();
+    }
+
+    /// Renumbers every reference into the function index space with `remap`: calls,
+    /// exports, table elements, the start function and the name section.
+    /// Only references are rewritten; `remap` must agree with how the import, function
+    /// and code sections are rearranged around it.
+    /// A name section that cannot be parsed cannot be renumbered either, so it is an
+    /// error, and nothing is rewritten.
+    pub fn remap_functions<F>(&mut self, remap: F) -> Result<(), Error>
+        where F: Fn(u32) -> u32
+    {
+        if self.custom_section("name").is_some() {
+            let module = mem::take(&mut self.module);
+            match module.parse_names() {
+                Ok(module) => self.module = module,
+                Err((_, module)) => {
+                    self.module = module;
+                    return Err(Error::Other("Cannot renumber functions in a malformed name \
+                                             section"));
+                }
+            }
+        }
+
+        for section in self.module.sections_mut() {
+            match section {
+                Section::Code(section) => {
+                    for body in section.bodies_mut() {
+                        for inst in body.code_mut().elements_mut() {
+                            if let Instruction::Call(id) = inst {
+                                *id = remap(*id);
+                            }
+                        }
+                    }
+                }
+                Section::Export(section) => {
+                    for export in section.entries_mut() {
+                        if let Internal::Function(id) = export.internal_mut() {
+                            *id = remap(*id);
+                        }
+                    }
+                }
+                Section::Element(section) => {
+                    for segment in section.entries_mut() {
+                        for id in segment.members_mut() {
+                            *id = remap(*id);
+                        }
+                    }
+                }
+                Section::Start(id) => *id = remap(*id),
+                Section::Name(NameSection::Function(section)) => {
+                    let names = section.names_mut();
+                    *names = names.iter().map(|(id, name)| (remap(id), name.clone())).collect();
+                }
+                Section::Name(NameSection::Local(section)) => {
+                    let names = section.local_names_mut();
+                    *names = names.iter().map(|(id, locals)| (remap(id), locals.clone())).collect();
+                }
+                _ => {}
+            }
+        }
+        self.load_function_names();
+        Ok(())
+    }
+
+    /// Appends a global to the module, and returns its index in the global index space.
+    pub fn push_global(&mut self, global: GlobalEntry) -> u32 {
+        self.section_mut(Section::Global(GlobalSection::default()), |section| {
+                match section {
+                    Section::Global(section) => Some(section.entries_mut()),
+                    _ => None,
+                }
+            })
+            .push(global);
+        self.module.globals_space() as u32 - 1
+    }
+
+    /// Adds an entry to the export section.
+    pub fn push_export(&mut self, export: ExportEntry) {
+        self.section_mut(Section::Export(ExportSection::default()), |section| {
+                match section {
+                    Section::Export(section) => Some(section.entries_mut()),
+                    _ => None,
+                }
+            })
+            .push(export);
+        self.load_function_names();
+    }
+
+    /// Reserves `bytes` bytes of zeroed linear memory in memory 0, and returns the
+    /// address of the reserved region.
+    /// Allocators that start at `__heap_base` hand out everything from there up to the
+    /// current size of memory, so in a module that exports `__heap_base` the region is
+    /// placed at its old value, and the global behind it is moved past the region.
+    /// Otherwise the region is placed past the initial size of memory, which
+    /// allocators that claim memory through `grow_memory` alone leave alone. Either way
+    /// the initial size grows to cover the region. A module whose memory is imported,
+    /// and so sized by the host, is refused.
+    /// If the module has no memory, one is created; if it is not exported, it is
+    /// exported as `memory` so the host can read the reserved region.
+    pub fn reserve_memory(&mut self, bytes: u32) -> Result<u32, Error> {
+        if self.imports().any(|import| matches!(import.external(), External::Memory(_))) {
+            return Err(Error::Other("Cannot reserve space in an imported memory"));
+        }
+        let heap_base = self.heap_base()?;
+
+        let memories = self.section_mut(Section::Memory(MemorySection::default()), |section| {
+            match section {
+                Section::Memory(section) => Some(section.entries_mut()),
+                _ => None,
+            }
+        });
+        if memories.is_empty() {
+            memories.push(MemoryType::new(0, None));
+        }
+        let limits = *memories[0].limits();
+        // The widest counters are `i64`s.
+        let base = match heap_base {
+            Some((_, heap_base)) => (heap_base as u64).div_ceil(8) * 8,
+            None => limits.initial() as u64 * WASM_PAGE_SIZE as u64,
+        };
+        // Allocators expect `__heap_base` to be aligned for any type.
+        let end = (base + bytes as u64).div_ceil(16) * 16;
+        let initial = limits.initial().max(end.div_ceil(WASM_PAGE_SIZE as u64) as u32);
+        // Linear memory is limited to 4GiB.
+        if end > u32::MAX as u64 || limits.maximum().is_some_and(|max| initial > max) {
+            return Err(Error::Other("Not enough room in linear memory to reserve"));
+        }
+        memories[0] = MemoryType::new(initial, limits.maximum());
+
+        if let Some((index, _)) = heap_base {
+            let global = &mut self.module.global_section_mut().expect("Found __heap_base")
+                .entries_mut()[index];
+            *global.init_expr_mut() =
+                InitExpr::new(vec![Instruction::I32Const(end as i32), Instruction::End]);
+        }
+
+        let memory_exported = self.exports().iter().any(|export| {
+            *export.internal() == Internal::Memory(0)
+        });
+        if !memory_exported {
+            self.push_export(ExportEntry::new(MEMORY.to_owned(), Internal::Memory(0)));
+        }
+
+        Ok(base as u32)
+    }
+
+    /// The index in the global section and the value of the global exported as
+    /// `__heap_base`, if the module exports one.
+    fn heap_base(&self) -> Result<Option<(usize, u32)>, Error> {
+        let global = match self.exports().iter().find(|export| export.field() == HEAP_BASE) {
+            Some(export) => {
+                match *export.internal() {
+                    Internal::Global(global) => global as usize,
+                    _ => return Err(Error::Other("__heap_base is not a global")),
+                }
+            }
+            None => return Ok(None),
+        };
+        let entry = global.checked_sub(self.module.import_count(ImportCountType::Global))
+            .and_then(|index| {
+                let section = self.module.global_section()?;
+                section.entries().get(index).map(|entry| (index, entry))
+            });
+        match entry.map(|(index, entry)| (index, entry.init_expr().code())) {
+            Some((index, &[Instruction::I32Const(value), Instruction::End])) => {
+                Ok(Some((index, value as u32)))
+            }
+            _ => Err(Error::Other("Cannot move a __heap_base that is not a constant global")),
+        }
+    }
+
+    /// Reserves `len` counters of `elem_bytes` bytes each (see `reserve_memory`), adds
+    /// the exported functions `base_export` and `len_export` that return the address of
+    /// the first counter and `len`, and sets the custom section `section` that says
+    /// what the counters count to `payload`. Returns the address of the first counter.
+    pub fn inject_counters(&mut self,
+                           len: usize,
+                           elem_bytes: u32,
+                           (base_export, len_export): (&str, &str),
+                           section: &str,
+                           payload: Vec<u8>)
+                           -> Result<u32, Error> {
+        let bytes = match (len as u64).checked_mul(elem_bytes as u64) {
+            Some(bytes) if bytes <= u32::MAX as u64 / 2 => bytes as u32,
+            _ => return Err(Error::Other("Too many counters to reserve")),
+        };
+        let base = self.reserve_memory(bytes)?;
+
+        let expose_ty = FunctionType::new(vec![], Some(ValueType::I32));
+        for &(name, value) in [(base_export, base as i32), (len_export, len as i32)].iter() {
+            let code = vec![Instruction::I32Const(value), Instruction::End];
+            let body = FuncBody::new(vec![], Instructions::new(code));
+            let id = self.push_function(expose_ty.clone(), body, Some(name));
+            self.push_export(ExportEntry::new(name.to_owned(), Internal::Function(id as u32)));
+        }
+        self.set_custom_section(section, payload);
+
+        Ok(base)
+    }
+
+    /// Payload of the custom section called `name`, if the module has one.
+    pub fn custom_section(&self, name: &str) -> Option<&[u8]> {
+        self.module.sections().iter().find_map(|section| match section {
+            Section::Custom(section) if section.name() == name => Some(section.payload()),
+            _ => None,
+        })
+    }
+
+    /// Sets the payload of the custom section called `name`, which is appended to the
+    /// module if it does not have one yet.
+    pub fn set_custom_section(&mut self, name: &str, payload: Vec<u8>) {
+        let sections = self.module.sections_mut();
+        let existing = sections.iter_mut().find_map(|section| match section {
+            Section::Custom(section) if section.name() == name => Some(section),
+            _ => None,
+        });
+        match existing {
+            Some(section) => *section.payload_mut() = payload,
+            None => {
+                let mut section = CustomSection::default();
+                *section.name_mut() = name.to_owned();
+                *section.payload_mut() = payload;
+                sections.push(Section::Custom(section));
+            }
+        }
+    }
+
+    /// Finds the section that `find` selects from, inserting `empty` in its place
+    /// in the section order if the module does not have one yet.
+    fn section_mut<T, F>(&mut self, empty: Section, find: F) -> &mut T
+        where F: Fn(&mut Section) -> Option<&mut T>
+    {
+        if !self.module.sections_mut().iter_mut().any(|section| find(section).is_some()) {
+            let id = section_id(&empty).expect("Only known sections can be inserted");
+            let sections = self.module.sections_mut();
+            let position = sections
+                .iter()
+                .position(|section| section_id(section).is_some_and(|other| other > id))
+                .unwrap_or_else(|| {
+                    // Insert after the last known section, ahead of any trailing
+                    // custom sections such as names.
+                    sections.iter().rposition(|section| section_id(section).is_some())
+                        .map_or(0, |last| last + 1)
+                });
+            sections.insert(position, empty);
+        }
+        self.module
+            .sections_mut()
+            .iter_mut()
+            .filter_map(find)
+            .next()
+            .expect("Section was just inserted")
     }
 
     /// Prints the index in the function index space, type signature, and instruction
@@ -279,21 +1226,61 @@ impl WasmModule {
     fn exported_function_names(&self) -> HashMap<usize, String> {
         let mut names = HashMap::new();
         for export in self.exports() {
-            match export.internal() {
-                Internal::Function(id) => {
-                    // NOTE(slim): `id` is an index into the function index space,
-                    // not the types section or the function section.
-                    let name = export.field().to_owned();
-                    names.insert(*id as usize, name);
-                }
-                // Skip over exports that aren't functions.
-                _ => {}
+            // Skip over exports that aren't functions.
+            if let Internal::Function(id) = export.internal() {
+                // NOTE(slim): `id` is an index into the function index space,
+                // not the types section or the function section.
+                let name = export.field().to_owned();
+                names.insert(*id as usize, name);
             }
         }
         names
     }
 
-    /// Function name for index of exported function in function index space.
+    /// Index of the function in slot `index` of table 0, as set by the element section.
+    /// Later segments take precedence. Segments with an offset that is not a constant,
+    /// such as an imported global, cannot be resolved and are skipped.
+    pub fn table_function(&self, index: u32) -> Option<usize> {
+        self.module
+            .elements_section()
+            .map_or(&[][..], |section| section.entries())
+            .iter()
+            .rev()
+            .filter(|segment| segment.index() == 0)
+            .filter_map(|segment| match segment.offset().code() {
+                [Instruction::I32Const(offset), Instruction::End] => {
+                    let slot = index.checked_sub(*offset as u32)?;
+                    segment.members().get(slot as usize)
+                }
+                _ => None,
+            })
+            .next()
+            .map(|&id| id as usize)
+    }
+
+    /// Index that function `id` logs its calls under, if it was instrumented. This is
+    /// `id` itself, except for the wrappers of imported functions.
+    pub fn logged_id(&self, id: usize) -> Option<usize> {
+        self.logged_ids().get(&id).cloned()
+    }
+
+    /// The index each instrumented function logs its calls under, as recorded in the
+    /// `wasm_trace.logged` section.
+    pub fn logged_ids(&self) -> HashMap<usize, usize> {
+        self.custom_section(LOGGED_SECTION)
+            .map_or(&[][..], |payload| payload)
+            .chunks_exact(8)
+            .map(|pair| {
+                let word = |i: usize| {
+                    u32::from_le_bytes([pair[i], pair[i + 1], pair[i + 2], pair[i + 3]]) as usize
+                };
+                (word(0), word(4))
+            })
+            .collect()
+    }
+
+    /// Function name for index in function index space, from the name section if
+    /// present, or otherwise from the export section.
     pub fn get_function_name(&self, id: usize) -> Option<&str> {
         self.function_names.get(&id).map(String::as_str)
     }
@@ -353,6 +1340,74 @@ impl WasmModule {
     }
 }
 
+/// Binary id of a known section, or `None` for custom sections, which may appear anywhere.
+fn section_id(section: &Section) -> Option<u8> {
+    match section {
+        Section::Unparsed { id, .. } if *id != 0 => Some(*id),
+        Section::Type(_) => Some(1),
+        Section::Import(_) => Some(2),
+        Section::Function(_) => Some(3),
+        Section::Table(_) => Some(4),
+        Section::Memory(_) => Some(5),
+        Section::Global(_) => Some(6),
+        Section::Export(_) => Some(7),
+        Section::Start(_) => Some(8),
+        Section::Element(_) => Some(9),
+        Section::Code(_) => Some(10),
+        Section::Data(_) => Some(11),
+        _ => None,
+    }
+}
+
+/// Whether `func` matches the `selection` of `options`, and is in its `scope`, which
+/// a selection that includes functions explicitly widens to every function.
+fn selected(func: &WasmFunction, options: &InstrumentOptions) -> bool {
+    let in_scope = options.scope == Scope::All || func.exported ||
+                   options.selection.has_includes();
+    in_scope && options.selection.matches(func)
+}
+
+/// The index that function `id` moves to when a function is imported at `import`.
+fn shifted_for_import(id: usize, import: usize) -> usize {
+    if id >= import { id + 1 } else { id }
+}
+
+/// Moves each of `ids` to where it goes when a function is imported at `import`.
+fn shift_for_import(ids: HashSet<usize>, import: usize) -> HashSet<usize> {
+    ids.into_iter().map(|id| shifted_for_import(id, import)).collect()
+}
+
+/// Entry kind for returning a value of type `ty`.
+fn return_kind(ty: ValueType) -> EntryKind {
+    match ty {
+        ValueType::I32 => EntryKind::FunctionReturnI32,
+        ValueType::I64 => EntryKind::FunctionReturnI64,
+        ValueType::F32 => EntryKind::FunctionReturnF32,
+        ValueType::F64 => EntryKind::FunctionReturnF64,
+    }
+}
+
+/// Entry kind for an argument of type `ty`.
+fn argument_kind(ty: ValueType) -> EntryKind {
+    match ty {
+        ValueType::I32 => EntryKind::ArgumentI32,
+        ValueType::I64 => EntryKind::ArgumentI64,
+        ValueType::F32 => EntryKind::ArgumentF32,
+        ValueType::F64 => EntryKind::ArgumentF64,
+    }
+}
+
+/// Instructions that replace the value of type `ty` on top of the stack with its
+/// bit pattern as an `i64`, which is how values are passed to the logger.
+fn value_bits(ty: ValueType) -> Vec<Instruction> {
+    match ty {
+        ValueType::I32 => vec![Instruction::I64ExtendUI32],
+        ValueType::I64 => vec![],
+        ValueType::F32 => vec![Instruction::I32ReinterpretF32, Instruction::I64ExtendUI32],
+        ValueType::F64 => vec![Instruction::I64ReinterpretF64],
+    }
+}
+
 #[derive(Debug, PartialEq)]
 /// WebAssembly function.
 pub struct WasmFunction<'a> {
@@ -361,10 +1416,31 @@ pub struct WasmFunction<'a> {
     name: Option<&'a str>,
     body: Option<&'a FuncBody>,
     source: SourceSection,
+    exported: bool,
 }
 
-/// Instructions a function body.
 impl<'a> WasmFunction<'a> {
+    /// Index in the function index space.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Name from the name section, or failing that the import or export section.
+    pub fn name(&self) -> Option<&'a str> {
+        self.name
+    }
+
+    /// Whether the function appears in the export section.
+    pub fn is_exported(&self) -> bool {
+        self.exported
+    }
+
+    /// Whether the function is imported, and therefore has no body.
+    pub fn is_imported(&self) -> bool {
+        self.source == SourceSection::Import
+    }
+
+    /// Instructions in the function body.
     pub fn instructions(&self) -> impl Iterator<Item = &Instruction> {
         self.body
             .map_or(Either::Left(iter::empty()),
@@ -415,9 +1491,125 @@ pub enum SourceSection {
 }
 
 #[cfg(test)]
+#[allow(array_into_iter,
+        clippy::bool_assert_comparison,
+        clippy::into_iter_on_ref,
+        clippy::iter_nth_zero,
+        clippy::unnecessary_map_or,
+        clippy::useless_vec)]
 mod test {
     use parity_wasm::elements::*;
-    use super::{WasmModule, WasmFunction, EntryKind};
+    use parity_wasm::builder;
+    use super::{WasmModule, WasmFunction, EntryKind, MEMORY, WASM_PAGE_SIZE};
+    use options::{Backend, Clock, InstrumentOptions, Scope};
+    use selection::Matcher;
+    use tracer::{CLOCK_IMPORT, EXPOSE_TRACER, EXPOSE_TRACER_LEN, LOG_CALL, LOG_IMPORT,
+                 LOG_IMPORT_MODULE};
+
+    fn function(params: Vec<ValueType>,
+                return_ty: Option<ValueType>,
+                instructions: Vec<Instruction>)
+                -> builder::FunctionDefinition {
+        builder::function()
+            .signature()
+            .with_params(params)
+            .with_return_type(return_ty)
+            .build()
+            .body()
+            .with_instructions(Instructions::new(instructions))
+            .build()
+            .build()
+    }
+
+    /// Builds a module with a bootstrapped tracer, an exported `entry` function and
+    /// an internal `inner` function:
+    ///
+    /// | id | name                  | exported |
+    /// |----|-----------------------|----------|
+    /// | 0  | `__log_call`          | yes      |
+    /// | 1  | `log_helper`          | no       |
+    /// | 2  | `__expose_tracer`     | yes      |
+    /// | 3  | `__expose_tracer_len` | yes      |
+    /// | 4  | `entry`               | yes      |
+    /// | 5  | `inner`               | no       |
+    fn traced_module() -> WasmModule {
+        use self::ValueType::I32;
+
+        let mut names = FunctionNameSection::default();
+        let all_names = [LOG_CALL, "log_helper", EXPOSE_TRACER, EXPOSE_TRACER_LEN, "entry", "inner"];
+        for (id, name) in all_names.iter().enumerate() {
+            names.names_mut().insert(id as u32, name.to_string());
+        }
+
+        let mut module = builder::module();
+        module.push_function(function(vec![I32, I32, ValueType::I64], None,
+                                      vec![Instruction::Call(1), Instruction::End]));
+        module.push_function(function(vec![], None, vec![Instruction::End]));
+        module.push_function(function(vec![], Some(I32),
+                                      vec![Instruction::I32Const(0), Instruction::End]));
+        module.push_function(function(vec![], Some(I32),
+                                      vec![Instruction::I32Const(0), Instruction::End]));
+        module.push_function(function(vec![I32], Some(I32),
+                                      vec![Instruction::GetLocal(0),
+                                           Instruction::Call(5),
+                                           Instruction::End]));
+        module.push_function(function(vec![I32], Some(I32),
+                                      vec![Instruction::GetLocal(0), Instruction::End]));
+
+        let module = module
+            .export().field(LOG_CALL).internal().func(0).build()
+            .export().field(EXPOSE_TRACER).internal().func(2).build()
+            .export().field(EXPOSE_TRACER_LEN).internal().func(3).build()
+            .export().field("entry").internal().func(4).build()
+            .with_section(Section::Name(NameSection::Function(names)))
+            .build();
+
+        WasmModule::from_module(module)
+    }
+
+    /// Builds a module whose function 0 is `__log_call`, followed by `functions`.
+    fn logged_module(functions: Vec<builder::FunctionDefinition>) -> WasmModule {
+        let mut module = builder::module();
+        module.push_function(function(vec![ValueType::I32, ValueType::I32, ValueType::I64],
+                                      None,
+                                      vec![Instruction::End]));
+        for func in functions {
+            module.push_function(func);
+        }
+        let module = module.export().field(LOG_CALL).internal().func(0).build().build();
+        WasmModule::from_module(module)
+    }
+
+    /// Refers to `inner` (5) in `traced_module` from a table element and the start
+    /// section, in addition to the call from `entry`.
+    fn reference_inner(module: &mut WasmModule) {
+        use self::Instruction::{End, I32Const};
+
+        module.section_mut(Section::Element(ElementSection::default()), |section| {
+                match section {
+                    Section::Element(section) => Some(section.entries_mut()),
+                    _ => None,
+                }
+            })
+            .push(ElementSegment::new(0, InitExpr::new(vec![I32Const(0), End]), vec![5]));
+        module.section_mut(Section::Start(5), |section| match section {
+            Section::Start(id) => Some(id),
+            _ => None,
+        });
+    }
+
+    fn is_instrumented(module: &WasmModule, id: usize) -> bool {
+        is_logged_by(module, id, 0)
+    }
+
+    /// Whether function `id` logs its calls through `logger`.
+    fn is_logged_by(module: &WasmModule, id: usize, logger: u32) -> bool {
+        let body = &module.function_bodies()[id - module.imported_functions_count()];
+        body.code().elements().starts_with(&[Instruction::I32Const(EntryKind::FunctionCall as i32),
+                                             Instruction::I32Const(id as i32),
+                                             Instruction::I64Const(0),
+                                             Instruction::Call(logger)])
+    }
 
     #[test]
     fn list_functions() {
@@ -577,20 +1769,24 @@ mod test {
         let after_insertion =
             vec![vec![Instruction::I32Const(EntryKind::FunctionCall as i32),
                       Instruction::I32Const(0),
+                      Instruction::I64Const(0),
                       Instruction::Call(mock_log_call),
 
                       Instruction::GetLocal(1),
                       Instruction::GetLocal(0),
                       Instruction::I32Add,
 
-                      Instruction::TeeLocal(0),
-                      Instruction::I32Const(EntryKind::FunctionReturnValue as i32),
-                      Instruction::GetLocal(0),
+                      Instruction::TeeLocal(2),
+                      Instruction::I32Const(EntryKind::FunctionReturnI32 as i32),
+                      Instruction::I32Const(0),
+                      Instruction::GetLocal(2),
+                      Instruction::I64ExtendUI32,
                       Instruction::Call(mock_log_call),
                       Instruction::End],
 
                  vec![Instruction::I32Const(EntryKind::FunctionCall as i32),
                       Instruction::I32Const(1),
+                      Instruction::I64Const(0),
                       Instruction::Call(mock_log_call),
 
                       Instruction::GetLocal(0),
@@ -599,43 +1795,56 @@ mod test {
                       Instruction::GetLocal(0),
                       Instruction::I32Add,
 
-                      Instruction::TeeLocal(0),
-                      Instruction::I32Const(EntryKind::FunctionReturnValue as i32),
-                      Instruction::GetLocal(0),
+                      Instruction::TeeLocal(1),
+                      Instruction::I32Const(EntryKind::FunctionReturnI32 as i32),
+                      Instruction::I32Const(1),
+                      Instruction::GetLocal(1),
+                      Instruction::I64ExtendUI32,
                       Instruction::Call(mock_log_call),
                       Instruction::End],
 
                  vec![Instruction::I32Const(EntryKind::FunctionCall as i32),
                       Instruction::I32Const(2),
+                      Instruction::I64Const(0),
                       Instruction::Call(mock_log_call),
 
                       Instruction::GetLocal(0),
                       Instruction::F64Const(4602678819172646912),
                       Instruction::F64Mul,
 
-                      Instruction::TeeLocal(0),
-                      Instruction::I32Const(EntryKind::FunctionReturnValue as i32),
-                      Instruction::GetLocal(0),
+                      Instruction::TeeLocal(1),
+                      Instruction::I32Const(EntryKind::FunctionReturnF64 as i32),
+                      Instruction::I32Const(2),
+                      Instruction::GetLocal(1),
+                      Instruction::I64ReinterpretF64,
                       Instruction::Call(mock_log_call),
                       Instruction::End],
 
                  vec![Instruction::I32Const(EntryKind::FunctionCall as i32),
                       Instruction::I32Const(3),
+                      Instruction::I64Const(0),
                       Instruction::Call(mock_log_call),
 
                       Instruction::GetLocal(0),
                       Instruction::I32Const(1),
                       Instruction::I32Shl,
 
-                      Instruction::TeeLocal(0),
-                      Instruction::I32Const(EntryKind::FunctionReturnValue as i32),
-                      Instruction::GetLocal(0),
+                      Instruction::TeeLocal(1),
+                      Instruction::I32Const(EntryKind::FunctionReturnI32 as i32),
+                      Instruction::I32Const(3),
+                      Instruction::GetLocal(1),
+                      Instruction::I64ExtendUI32,
                       Instruction::Call(mock_log_call),
                       Instruction::End]];
 
         let mut working = CodeSection::with_bodies(module.function_bodies().to_vec());
         module
-            .add_tracing_instructions(mock_log_call as usize, &mut working)
+            .add_tracing_instructions(mock_log_call as usize,
+                                      &[],
+                                      &module.selected_function_ids(&Default::default()),
+                                      &InstrumentOptions::default(),
+                                      None,
+                                      &mut working)
             .unwrap();
 
         for (i, f) in working.bodies().iter().enumerate() {
@@ -646,5 +1855,635 @@ mod test {
 
     }
 
+    #[test]
+    fn tracer_functions_are_transitively_excluded() {
+        let module = traced_module();
+        let mut excluded = module.tracer_function_ids().into_iter().collect::<Vec<usize>>();
+        excluded.sort();
+        assert_eq!(excluded, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn instrument_exported_functions() {
+        let mut module = traced_module();
+        module.instrument_module().unwrap();
+        let instrumented = (0..6).filter(|&id| is_instrumented(&module, id)).collect::<Vec<_>>();
+        assert_eq!(instrumented, vec![4]);
+    }
+
+    #[test]
+    fn instrument_all_functions() {
+        let mut module = traced_module();
+        let options = InstrumentOptions { scope: Scope::All, ..Default::default() };
+        module.instrument_module_with(&options).unwrap();
+        let instrumented = (0..6).filter(|&id| is_instrumented(&module, id)).collect::<Vec<_>>();
+        assert_eq!(instrumented, vec![4, 5]);
+        assert_eq!(module.get_function_name(5), Some("inner"));
+    }
+
+    #[test]
+    fn instrument_selected_functions() {
+        let mut module = traced_module();
+        let mut options = InstrumentOptions { scope: Scope::All, ..Default::default() };
+        options.selection.include("*".parse().unwrap()).exclude("inner".parse().unwrap());
+        module.instrument_module_with(&options).unwrap();
+        let instrumented = (0..6).filter(|&id| is_instrumented(&module, id)).collect::<Vec<_>>();
+        assert_eq!(instrumented, vec![4]);
+    }
+
+    #[test]
+    fn include_unexported_functions() {
+        // An include picks `inner` even though only exported functions are in scope.
+        let mut module = traced_module();
+        let mut options = InstrumentOptions::default();
+        options.selection.include("inner".parse().unwrap());
+        module.instrument_module_with(&options).unwrap();
+        let instrumented = (0..6).filter(|&id| is_instrumented(&module, id)).collect::<Vec<_>>();
+        assert_eq!(instrumented, vec![5]);
+    }
+
+    #[test]
+    fn log_branches_out_of_function() {
+        use self::Instruction::*;
+        use self::ValueType::I32;
+
+        let branches = function(vec![I32], Some(I32),
+                                vec![I32Const(7),
+                                     GetLocal(0),
+                                     BrIf(0),
+                                     Drop,
+                                     Block(BlockType::Value(I32)),
+                                     I32Const(8),
+                                     GetLocal(0),
+                                     BrTable(vec![0, 1].into_boxed_slice(), 0),
+                                     End,
+                                     I32Const(1),
+                                     I32Add,
+                                     Br(0),
+                                     End]);
+        let void_br_if = function(vec![I32], None, vec![GetLocal(0), BrIf(0), End]);
+
+        let mut module = logged_module(vec![branches, void_br_if]);
+        let options = InstrumentOptions { scope: Scope::All, ..Default::default() };
+        module.instrument_module_with(&options).unwrap();
+
+        let call = EntryKind::FunctionCall as i32;
+        let value = EntryKind::FunctionReturnI32 as i32;
+        let void = EntryKind::FunctionReturnVoid as i32;
+        let log_value = vec![I32Const(value), I32Const(1), GetLocal(1), I64ExtendUI32, Call(0)];
+        let log_void = vec![I32Const(void), I32Const(2), I64Const(0), Call(0)];
+        let expected = vec![[vec![I32Const(call), I32Const(1), I64Const(0), Call(0),
+                                  I32Const(7),
+                                  GetLocal(0),
+                                  // br_if 0
+                                  SetLocal(2), TeeLocal(1), GetLocal(2),
+                                  If(BlockType::NoResult)],
+                             log_value.clone(),
+                             vec![GetLocal(1), Return,
+                                  End,
+                                  Drop,
+                                  Block(BlockType::Value(I32)),
+                                  I32Const(8),
+                                  GetLocal(0),
+                                  // br_table [0, 1] 0
+                                  SetLocal(2), SetLocal(1),
+                                  Block(BlockType::Value(I32)),
+                                  GetLocal(1), GetLocal(2), BrTable(vec![1, 0].into_boxed_slice(), 1),
+                                  End,
+                                  TeeLocal(1)],
+                             log_value.clone(),
+                             vec![Return,
+                                  End,
+                                  I32Const(1),
+                                  I32Add,
+                                  // br 0
+                                  TeeLocal(1)],
+                             log_value.clone(),
+                             vec![Br(0), TeeLocal(1)],
+                             log_value,
+                             vec![End]].concat(),
+                            [vec![I32Const(call), I32Const(2), I64Const(0), Call(0),
+                                  GetLocal(0),
+                                  If(BlockType::NoResult)],
+                             log_void.clone(),
+                             vec![Return, End],
+                             log_void,
+                             vec![End]].concat()];
+
+        for (body, expected) in module.function_bodies()[1..].iter().zip(expected) {
+            assert_eq!(body.code().elements(), &expected[..]);
+        }
+        assert_eq!(module.function_bodies()[1].locals(),
+                   &[Local::new(1, I32), Local::new(1, I32)]);
+    }
+
+    #[test]
+    fn log_return_value_bits() {
+        use self::Instruction::*;
+
+        let returns = [(ValueType::I64, I64Const(-1), EntryKind::FunctionReturnI64, vec![]),
+                       (ValueType::F32, F32Const(0), EntryKind::FunctionReturnF32,
+                        vec![I32ReinterpretF32, I64ExtendUI32]),
+                       (ValueType::F64, F64Const(0), EntryKind::FunctionReturnF64,
+                        vec![I64ReinterpretF64])];
+        let functions = returns
+            .iter()
+            .map(|(ty, constant, _, _)| function(vec![], Some(*ty), vec![constant.clone(), End]))
+            .collect();
+
+        let mut module = logged_module(functions);
+        let options = InstrumentOptions { scope: Scope::All, ..Default::default() };
+        module.instrument_module_with(&options).unwrap();
+
+        for (i, (_, _, kind, bits)) in returns.iter().enumerate() {
+            let id = i as i32 + 1;
+            let body = &module.function_bodies()[id as usize];
+            let epilogue = [vec![TeeLocal(0), I32Const(*kind as i32), I32Const(id), GetLocal(0)],
+                            bits.clone(),
+                            vec![Call(0), End]].concat();
+            assert!(body.code().elements().ends_with(&epilogue));
+        }
+    }
+
+    #[test]
+    fn log_arguments() {
+        use self::Instruction::*;
+        use self::ValueType::{I32, F64};
+
+        let mut module = logged_module(vec![function(vec![I32, F64], None, vec![End])]);
+        let options = InstrumentOptions {
+            scope: Scope::All,
+            log_arguments: true,
+            ..Default::default()
+        };
+        module.instrument_module_with(&options).unwrap();
+
+        let prologue = vec![I32Const(EntryKind::FunctionCall as i32), I32Const(1), I64Const(0),
+                            Call(0),
+                            I32Const(EntryKind::ArgumentI32 as i32), I32Const(1), GetLocal(0),
+                            I64ExtendUI32, Call(0),
+                            I32Const(EntryKind::ArgumentF64 as i32), I32Const(1), GetLocal(1),
+                            I64ReinterpretF64, Call(0)];
+        assert!(module.function_bodies()[1].code().elements().starts_with(&prologue));
+    }
+
+    #[test]
+    fn log_to_host_import() {
+        use self::Instruction::*;
+
+        let mut module = traced_module();
+        reference_inner(&mut module);
+
+        let options = InstrumentOptions {
+            backend: Backend::HostImport,
+            ..Default::default()
+        };
+        module.instrument_module_with(&options).unwrap();
+
+        // The logger is imported, ahead of every defined function.
+        let import = module.imports().next().unwrap();
+        assert_eq!((import.module(), import.field()), (LOG_IMPORT_MODULE, LOG_IMPORT));
+        assert_eq!(module.imported_functions_count(), 1);
+
+        // Every reference to a defined function is shifted by one.
+        let exported = module.exports()
+            .iter()
+            .map(|export| (export.field(), *export.internal()))
+            .collect::<Vec<_>>();
+        assert_eq!(exported,
+                   vec![(LOG_CALL, Internal::Function(1)),
+                        (EXPOSE_TRACER, Internal::Function(3)),
+                        (EXPOSE_TRACER_LEN, Internal::Function(4)),
+                        ("entry", Internal::Function(5))]);
+        assert_eq!(module.module.elements_section().unwrap().entries()[0].members(), &[6]);
+        assert_eq!(module.module.start_section(), Some(6));
+        assert_eq!(module.get_function_name(6), Some("inner"));
+        assert_eq!(module.get_function_name(1), Some(LOG_CALL));
+
+        // `entry` logs through the import under its new index, and still calls `inner`.
+        let entry = module.function_bodies()[4].code().elements();
+        assert!(entry.starts_with(&[I32Const(EntryKind::FunctionCall as i32),
+                                    I32Const(5),
+                                    I64Const(0),
+                                    Call(0)]));
+        assert!(entry.contains(&Call(6)));
+
+        // The buffer tracer itself is still left alone.
+        assert_eq!(module.function_bodies()[0].code().elements(), &[Call(2), End]);
+
+        // Indices select from the module as it was given: `inner` (5), now at 6.
+        let mut module = traced_module();
+        let mut options = InstrumentOptions {
+            backend: Backend::HostImport,
+            ..Default::default()
+        };
+        options.selection.include(Matcher::Indices(5..6));
+        module.instrument_module_with(&options).unwrap();
+        assert!(!is_logged_by(&module, 5, 0));
+        assert!(is_logged_by(&module, 6, 0));
+    }
+
+    #[test]
+    fn log_timestamps() {
+        use self::Instruction::*;
+
+        // A counter is read after logging the call.
+        let mut module = traced_module();
+        let options = InstrumentOptions {
+            timestamps: Some(Clock::Counter),
+            ..Default::default()
+        };
+        module.instrument_module_with(&options).unwrap();
+        let entry = module.function_bodies()[4].code().elements();
+        assert!(entry.starts_with(&[I32Const(EntryKind::FunctionCall as i32),
+                                    I32Const(4),
+                                    I64Const(0),
+                                    Call(0),
+                                    I32Const(EntryKind::Timestamp as i32),
+                                    I32Const(4),
+                                    GetGlobal(0),
+                                    I64Const(1),
+                                    I64Add,
+                                    SetGlobal(0),
+                                    GetGlobal(0),
+                                    Call(0)]));
+        assert!(entry.ends_with(&[I32Const(EntryKind::Timestamp as i32),
+                                  I32Const(4),
+                                  GetGlobal(0),
+                                  I64Const(1),
+                                  I64Add,
+                                  SetGlobal(0),
+                                  GetGlobal(0),
+                                  Call(0),
+                                  End]));
+
+        // The host clock is imported, which shifts the logger and `entry` by one.
+        let mut module = traced_module();
+        let options = InstrumentOptions {
+            timestamps: Some(Clock::Host),
+            ..Default::default()
+        };
+        module.instrument_module_with(&options).unwrap();
+        assert_eq!(module.imported_function_id(LOG_IMPORT_MODULE, CLOCK_IMPORT), Some(0));
+        let entry = module.function_bodies()[4].code().elements();
+        assert!(entry.starts_with(&[I32Const(EntryKind::FunctionCall as i32),
+                                    I32Const(5),
+                                    I64Const(0),
+                                    Call(1),
+                                    I32Const(EntryKind::Timestamp as i32),
+                                    I32Const(5),
+                                    Call(0),
+                                    Call(1)]));
+
+        // With the logger imported too, `entry` (4) moves up by two, but indices still
+        // select from the module as it was given.
+        let mut module = traced_module();
+        let mut options = InstrumentOptions {
+            backend: Backend::HostImport,
+            timestamps: Some(Clock::Host),
+            ..Default::default()
+        };
+        options.selection.include(Matcher::Indices(4..5));
+        module.instrument_module_with(&options).unwrap();
+        assert_eq!(module.imported_function_id(LOG_IMPORT_MODULE, LOG_IMPORT), Some(1));
+        assert!(is_logged_by(&module, 6, 1));
+        assert!(!is_logged_by(&module, 7, 1));
+    }
+
+    #[test]
+    fn log_call_sites() {
+        use self::Instruction::*;
+
+        let mut module = traced_module();
+        let options = InstrumentOptions {
+            call_sites: true,
+            ..Default::default()
+        };
+        module.instrument_module_with(&options).unwrap();
+
+        // `entry` calls `inner` at offset 1 of its original body.
+        let site = (5 << 32) | 1;
+        let entry = module.function_bodies()[4].code().elements();
+        let call = entry.iter().position(|inst| *inst == Call(5)).unwrap();
+        assert_eq!(&entry[call - 4..call + 5],
+                   &[I32Const(EntryKind::CallSite as i32),
+                     I32Const(4),
+                     I64Const(site),
+                     Call(0),
+                     Call(5),
+                     I32Const(EntryKind::CallSiteReturn as i32),
+                     I32Const(4),
+                     I64Const(site),
+                     Call(0)]);
+
+        // Calls to the logger are not call sites.
+        let sites = entry.iter()
+            .filter(|inst| **inst == I32Const(EntryKind::CallSite as i32))
+            .count();
+        assert_eq!(sites, 1);
+    }
+
+    #[test]
+    fn log_indirect_calls() {
+        use self::Instruction::*;
+        use self::ValueType::I32;
+
+        // `caller` (1) calls `callee` (2) through slot 0 of the table.
+        let caller = function(vec![I32], Some(I32),
+                              vec![GetLocal(0), I32Const(0), CallIndirect(1, 0), End]);
+        let callee = function(vec![I32], Some(I32), vec![GetLocal(0), End]);
+        let mut module = logged_module(vec![caller, callee]);
+        module.section_mut(Section::Element(ElementSection::default()), |section| {
+                match section {
+                    Section::Element(section) => Some(section.entries_mut()),
+                    _ => None,
+                }
+            })
+            .push(ElementSegment::new(0, InitExpr::new(vec![I32Const(0), End]), vec![2]));
+        let options = InstrumentOptions {
+            scope: Scope::All,
+            indirect_calls: true,
+            ..Default::default()
+        };
+        module.instrument_module_with(&options).unwrap();
+
+        // The table index is logged with the offset of `call_indirect`, and stays
+        // on the stack. Local 2 comes after the parameter and the return value.
+        let body = module.function_bodies()[1].code().elements();
+        let call = body.iter().position(|inst| *inst == CallIndirect(1, 0)).unwrap();
+        assert_eq!(&body[call - 10..call],
+                   &[TeeLocal(2),
+                     I32Const(EntryKind::IndirectCall as i32),
+                     I32Const(1),
+                     GetLocal(2),
+                     I64ExtendUI32,
+                     I64Const(32),
+                     I64Shl,
+                     I64Const(2),
+                     I64Or,
+                     Call(0)]);
+
+        assert_eq!(module.table_function(0), Some(2));
+        assert_eq!(module.table_function(1), None);
+        assert_eq!(module.logged_id(2), Some(2));
+        assert_eq!(module.logged_id(0), None);
+    }
+
+    #[test]
+    fn count_blocks() {
+        use self::Instruction::*;
+        use coverage::{Block, BlockKind};
+        use counts::{increment, CounterWidth};
+
+        let branch = function(vec![ValueType::I32], None,
+                              vec![GetLocal(0),
+                                   If(BlockType::NoResult),
+                                   Loop(BlockType::NoResult),
+                                   End,
+                                   Else,
+                                   End,
+                                   GetLocal(0),
+                                   If(BlockType::NoResult),
+                                   End,
+                                   End]);
+        let mut module = logged_module(vec![branch]);
+        let options = InstrumentOptions {
+            scope: Scope::All,
+            ..Default::default()
+        };
+        let blocks = module.instrument_coverage(&options).unwrap();
+        let block = |offset, kind| Block { function: 1, offset, kind };
+        assert_eq!(blocks,
+                   [block(0, BlockKind::Entry),
+                    block(1, BlockKind::If),
+                    block(2, BlockKind::Loop),
+                    block(4, BlockKind::Else),
+                    block(7, BlockKind::If),
+                    block(8, BlockKind::ImplicitElse)]);
+
+        // The module has no memory, so the counters start at address 0 of a new one.
+        let counter = |i: u32| increment(i * 4, CounterWidth::I32);
+        let expected = [counter(0),
+                        vec![GetLocal(0), If(BlockType::NoResult)],
+                        counter(1),
+                        vec![Loop(BlockType::NoResult)],
+                        counter(2),
+                        vec![End, Else],
+                        counter(3),
+                        vec![End, GetLocal(0), If(BlockType::NoResult)],
+                        counter(4),
+                        // The `if` without an `else` gets one.
+                        vec![Else],
+                        counter(5),
+                        vec![End, End]]
+            .concat();
+        assert_eq!(module.function_bodies()[1].code().elements(), &expected[..]);
+
+        // The tracer is left alone.
+        assert_eq!(module.function_bodies()[0].code().elements(), &[End]);
+    }
+
+    #[test]
+    fn wrap_imported_functions() {
+        use self::Instruction::*;
 
+        let mut module = WasmModule::from_file("./tests/imports.wasm").unwrap();
+        let options = InstrumentOptions {
+            trace_imports: true,
+            ..Default::default()
+        };
+        module.instrument_module_with(&options).unwrap();
+
+        // The wrapper is appended after the injected runtime, and logs under the
+        // index of `printf` (0). The module has no name section to name it in.
+        let wrapper = module.functions().last().unwrap();
+        assert_eq!(wrapper.id(), 11);
+        assert_eq!(wrapper.name(), None);
+        let body = wrapper.instructions().cloned().collect::<Vec<Instruction>>();
+        assert!(body.starts_with(&[I32Const(EntryKind::FunctionCall as i32), I32Const(0)]));
+        assert!(body.contains(&Call(0)));
+
+        // `_Z2hiv` calls the wrapper instead of the import.
+        let hi = module.function_bodies()[0].code().elements();
+        assert!(hi.contains(&Call(wrapper.id() as u32)));
+        assert!(!hi.contains(&Call(0)));
+
+        // Both are recorded with the index they log under.
+        let hi_id = module.imported_functions_count();
+        assert_eq!(module.logged_id(wrapper.id()), Some(0));
+        assert_eq!(module.logged_id(hi_id), Some(hi_id));
+        assert_eq!(module.logged_id(0), None);
+
+        // Wrapping several imports gives the same module every time.
+        let instrument = || {
+            let mut module = WasmModule::from_file("./tests/more-imports.wasm").unwrap();
+            module.instrument_module_with(&options).unwrap();
+            let imports = module.imported_functions_count();
+            assert!(module.logged_ids().values().filter(|&&id| id < imports).count() > 1);
+            module.into_bytes().unwrap()
+        };
+        assert_eq!(instrument(), instrument());
+    }
+
+    #[test]
+    fn insert_defined_function() {
+        use self::Instruction::*;
+
+        let mut module = traced_module();
+        reference_inner(&mut module);
+        // The body already uses the new numbering, so it is not renumbered.
+        let body = FuncBody::new(vec![], Instructions::new(vec![Call(6), End]));
+        module.insert_function(5, FunctionType::default(), body, Some("inserted")).unwrap();
+
+        // The new function takes index 5, and `inner` moves to 6 everywhere.
+        assert_eq!(module.get_function_name(5), Some("inserted"));
+        assert_eq!(module.get_function_name(6), Some("inner"));
+        assert_eq!(module.function_bodies()[5].code().elements(), &[Call(6), End]);
+        assert_eq!(module.function_bodies()[4].code().elements(),
+                   &[GetLocal(0), Call(6), End]);
+        assert_eq!(module.module.elements_section().unwrap().entries()[0].members(), &[6]);
+        assert_eq!(module.module.start_section(), Some(6));
+
+        // Functions before it keep their indices.
+        assert_eq!(module.function_bodies()[0].code().elements(), &[Call(1), End]);
+        assert!(module.exports().iter().any(|export| {
+            export.field() == "entry" && *export.internal() == Internal::Function(4)
+        }));
+        let types = module.functions().map(|f| f.ty.clone()).collect::<Vec<_>>();
+        assert_eq!(types[5], Type::Function(FunctionType::default()));
+
+        let body = FuncBody::new(vec![], Instructions::new(vec![End]));
+        assert!(module.insert_function(8, FunctionType::default(), body, None).is_err());
+    }
+
+    #[test]
+    fn import_function_after_imports() {
+        let mut module = WasmModule::from_file("./tests/imports.wasm").unwrap();
+        let before = module.functions()
+            .map(|f| (f.name().map(str::to_owned), f.is_imported()))
+            .collect::<Vec<_>>();
+
+        let ty = FunctionType::new(vec![ValueType::I32], None);
+        assert_eq!(module.import_function("env", "extra", ty).unwrap(), 1);
+
+        // Existing imports stay put, and defined functions move up by one.
+        let after = module.functions()
+            .map(|f| (f.name().map(str::to_owned), f.is_imported()))
+            .collect::<Vec<_>>();
+        assert_eq!(after[0], before[0]);
+        assert_eq!(after[1], (Some("extra".to_owned()), true));
+        assert_eq!(&after[2..], &before[1..]);
+        assert!(module.functions().nth(2).unwrap().is_exported());
+    }
+
+    #[test]
+    fn remap_function_references() {
+        let mut module = traced_module();
+        reference_inner(&mut module);
+        // Swap `entry` and `inner`. Only references are rewritten, so the bodies stay
+        // where they are.
+        let swap = |id| match id {
+            4 => 5,
+            5 => 4,
+            id => id,
+        };
+        module.remap_functions(swap).unwrap();
+        assert_eq!(module.get_function_name(4), Some("inner"));
+        assert_eq!(module.get_function_name(5), Some("entry"));
+        assert!(module.exports().iter().any(|export| {
+            export.field() == "entry" && *export.internal() == Internal::Function(5)
+        }));
+        assert_eq!(module.module.start_section(), Some(4));
+        assert_eq!(module.module.elements_section().unwrap().entries()[0].members(), &[4]);
+        // `entry`'s body, still at 4, calls `inner` at its new index, and the tracer's
+        // call to its helper, which did not move, is left alone.
+        assert_eq!(module.function_bodies()[4].code().elements()[1], Instruction::Call(4));
+        assert_eq!(module.function_bodies()[0].code().elements()[0], Instruction::Call(1));
+
+        // A name section that did not parse is not left stale.
+        let mut module = traced_module();
+        module.module.sections_mut().retain(|section| !matches!(section, Section::Name(_)));
+        module.set_custom_section("name", vec![1, 5, 9]);
+        assert!(module.remap_functions(swap).is_err());
+        assert_eq!(module.custom_section("name"), Some(&[1, 5, 9][..]));
+        assert_eq!(module.function_bodies()[4].code().elements()[1], Instruction::Call(5));
+    }
+
+    #[test]
+    fn reserve_memory() {
+        let mut module = WasmModule::from_file("./tests/enter-exit-count.wasm").unwrap();
+        let initial = |module: &WasmModule| {
+            module.module.memory_section().unwrap().entries()[0].limits().initial()
+        };
+        let before = initial(&module);
+        let base = module.reserve_memory(WASM_PAGE_SIZE + 1).unwrap();
+        assert_eq!(base, before * WASM_PAGE_SIZE);
+        assert_eq!(initial(&module), before + 2);
+
+        // A module without memory gets one, exported for the host.
+        let mut module = WasmModule::from_module(builder::module().build());
+        assert_eq!(module.reserve_memory(1).unwrap(), 0);
+        assert_eq!(initial(&module), 1);
+        assert!(module.exports().iter().any(|export| export.field() == MEMORY));
+
+        // Memory that an allocator hands out from `__heap_base` is reserved from there,
+        // and `__heap_base` moves past it.
+        let mut module = WasmModule::from_module(builder::module()
+                                                     .memory()
+                                                     .with_min(1)
+                                                     .build()
+                                                     .global()
+                                                     .value_type()
+                                                     .i32()
+                                                     .init_expr(Instruction::I32Const(65530))
+                                                     .build()
+                                                     .export()
+                                                     .field("__heap_base")
+                                                     .internal()
+                                                     .global(0)
+                                                     .build()
+                                                     .build());
+        assert_eq!(module.reserve_memory(100).unwrap(), 65536);
+        assert_eq!(initial(&module), 2);
+        assert_eq!(module.module.global_section().unwrap().entries()[0].init_expr().code(),
+                   [Instruction::I32Const(65648), Instruction::End]);
+
+        // Memory sized by the host is refused.
+        let mut module = WasmModule::from_module(builder::module()
+                                                     .import()
+                                                     .module("env")
+                                                     .field("memory")
+                                                     .external()
+                                                     .memory(1, None)
+                                                     .build()
+                                                     .build());
+        assert!(module.reserve_memory(1).is_err());
+    }
+
+    #[test]
+    fn inject_counters() {
+        use self::Instruction::{End, I32Const};
+
+        let names = ("__counters", "__counters_len");
+        let memory = builder::module().memory().with_min(1).build().build();
+        let mut module = WasmModule::from_module(memory);
+        let base = module.inject_counters(3, 8, names, "counters", vec![1, 2, 3]).unwrap();
+        assert_eq!(base, WASM_PAGE_SIZE);
+
+        // The exported functions and the custom section survive serialization.
+        let bytes = module.into_bytes().unwrap();
+        let module = WasmModule::from_module(::parity_wasm::deserialize_buffer(&bytes).unwrap());
+        for &(name, value) in [(names.0, base as i32), (names.1, 3)].iter() {
+            let func = module.functions().find(|func| func.name() == Some(name)).unwrap();
+            assert!(func.is_exported());
+            assert_eq!(func.instructions().cloned().collect::<Vec<Instruction>>(),
+                       [I32Const(value), End]);
+        }
+        assert!(module.exports().iter().any(|export| export.field() == MEMORY));
+        assert_eq!(module.custom_section("counters"), Some(&[1, 2, 3][..]));
+
+        // Counters that do not fit in linear memory are refused.
+        let mut module = WasmModule::from_module(builder::module().build());
+        assert!(module.inject_counters(usize::MAX / 4, 8, names, "counters", vec![]).is_err());
+    }
 }
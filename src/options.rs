@@ -0,0 +1,85 @@
+//! Settings that control how a module is instrumented.
+
+use selection::Selection;
+use tracer::DEFAULT_CAPACITY;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+/// Which functions in the code section receive tracing instructions.
+pub enum Scope {
+    /// Only functions that appear in the export section.
+    #[default]
+    Exported,
+    /// Every function with a body, including private helpers and library internals.
+    All,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+/// Where the injected prologues and epilogues send their entries.
+pub enum Backend {
+    /// Call the module's exported `__log_call`, which records entries in a buffer
+    /// in linear memory. A tracer runtime is injected if the module has none.
+    #[default]
+    Buffer,
+    /// Call the imported `wasm_trace.log`, so that the host receives entries as
+    /// they happen.
+    HostImport,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Where timestamps come from.
+pub enum Clock {
+    /// Call the imported `wasm_trace.now`, which returns the host's time as an `i64`.
+    Host,
+    /// Read a counter that goes up by one on every read, so that time is measured
+    /// in log entries.
+    Counter,
+    /// Read the number of instructions run so far, counted per basic block in every
+    /// function but the tracer's (see `fuel`), so that time is the same on every run.
+    Instructions,
+}
+
+#[derive(Debug, Clone)]
+/// Options for `WasmModule::instrument_module_with`.
+pub struct InstrumentOptions {
+    /// Which functions to instrument.
+    pub scope: Scope,
+    /// Narrows down the functions in `scope`, or, if it includes functions, picks
+    /// them from every function.
+    pub selection: Selection,
+    /// Log the value of each argument on entry to a function.
+    pub log_arguments: bool,
+    /// Where to send log entries.
+    pub backend: Backend,
+    /// Also log just before and after every call instruction in the instrumented
+    /// functions, with the instruction's offset and the callee. This traces calls
+    /// into imported functions, which have no body to instrument.
+    pub call_sites: bool,
+    /// Wrap each selected imported function in a function that logs its call and
+    /// return, and call the wrapper instead, so that calls to the host are traced
+    /// like any other.
+    pub trace_imports: bool,
+    /// Log the table index that each `call_indirect` in the instrumented functions
+    /// calls through, so that the target can be resolved from the element section.
+    pub indirect_calls: bool,
+    /// Follow every call and return entry with a timestamp, if set.
+    pub timestamps: Option<Clock>,
+    /// Number of entries kept by an injected tracer runtime. A tracer that the
+    /// module already has keeps its own capacity.
+    pub capacity: usize,
+}
+
+impl Default for InstrumentOptions {
+    fn default() -> Self {
+        InstrumentOptions {
+            scope: Scope::default(),
+            selection: Selection::default(),
+            log_arguments: false,
+            backend: Backend::default(),
+            call_sites: false,
+            trace_imports: false,
+            indirect_calls: false,
+            timestamps: None,
+            capacity: DEFAULT_CAPACITY,
+        }
+    }
+}
@@ -1,98 +1,155 @@
-use std::fmt::Debug;
-use std::collections::VecDeque;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt::Debug;
 
 /// Ring buffer data structure tracks a fixed size of function calls.
-/// Behaves like a FIFO queue.
+/// Behaves like a FIFO queue that drops its oldest element when full.
+/// Elements live in a fixed array that is allocated once, and are never moved:
+/// the oldest element is at index `head` once the buffer has wrapped, and at
+/// index 0 before.
 #[derive(Debug)]
 pub struct RingBuffer<T> {
-    data: VecDeque<T>,
-    capacity: usize,
+    data: Box<[T]>,
+    /// Index the next element is written to, up to `capacity`.
+    head: usize,
+    /// Whether an element has been overwritten.
+    wrapped: bool,
 }
 
-impl<T: Debug> RingBuffer<T> {
+impl<T: Debug + Default> RingBuffer<T> {
     /// Initialize a new ring buffer with a given capacity.
     pub fn new(capacity: usize) -> Self {
         RingBuffer {
-            data: VecDeque::with_capacity(capacity),
-            capacity,
+            data: (0..capacity).map(|_| T::default()).collect::<Vec<T>>().into_boxed_slice(),
+            head: 0,
+            wrapped: false,
         }
     }
+}
 
+impl<T: Debug> RingBuffer<T> {
     /// Appends an element to the end of the buffer.
-    /// If the buffer is filled to capacity, the oldest element is removed.
+    /// If the buffer is filled to capacity, the oldest element is overwritten.
     pub fn enqueue(&mut self, item: T) {
-        if self.len() > self.capacity {
-            unreachable!();
+        if self.data.is_empty() {
+            return;
         }
-        if self.len() == self.capacity {
-            self.data.pop_front();
+        if self.head == self.data.len() {
+            self.head = 0;
+            self.wrapped = true;
         }
-        self.data.push_back(item);
+        self.data[self.head] = item;
+        self.head += 1;
     }
 
-    #[allow(dead_code)]
-    /// Dequeues an item from the front of the buffer.
-    pub fn dequeue(&mut self) -> Option<T> {
-        self.data.pop_front()
+    /// Empties the buffer. Items are overwritten as new ones are enqueued.
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.wrapped = false;
     }
 
     /// Returns the number of items in the buffer.
     pub fn len(&self) -> usize {
+        if self.wrapped {
+            self.data.len()
+        } else {
+            self.head
+        }
+    }
+
+    /// Returns the number of items the buffer holds before dropping old ones.
+    pub fn capacity(&self) -> usize {
         self.data.len()
     }
 
+    /// Returns the index in `as_slice` that the next item is written to, and
+    /// that holds the oldest item once the buffer has wrapped.
+    pub fn head(&self) -> usize {
+        self.head
+    }
+
+    /// Returns whether older items have been overwritten.
+    pub fn is_wrapped(&self) -> bool {
+        self.wrapped
+    }
+
     #[allow(dead_code)]
-    /// Returns an iterator over the buffer contents.
+    /// Returns an iterator over the buffer contents, oldest first.
     pub fn iter(&self) -> impl Iterator<Item = &T> {
-        self.data.iter()
+        let (newer, older) = self.as_slice().split_at(self.head);
+        older.iter().chain(newer)
     }
 
-    /// Returns a slice of the buffer contents.
+    /// Returns a slice of the buffer contents, in the order they are stored.
+    /// Once the buffer has wrapped, the oldest item is at `head`.
     pub fn as_slice(&self) -> &[T] {
-        let (front, back) = self.data.as_slices();
-        assert!(back.is_empty(), "elements are only inserted at the back");
-        front
+        &self.data[..self.len()]
     }
 }
 
 #[cfg(test)]
 mod test_ring_buffer {
+    use alloc::vec;
+    use alloc::vec::Vec;
     use super::RingBuffer;
 
     #[test]
     fn initialize() {
-        let mut buf: RingBuffer<usize> = RingBuffer::new(4);
+        let buf: RingBuffer<usize> = RingBuffer::new(4);
         assert_eq!(buf.len(), 0);
-        assert_eq!(buf.capacity, 4);
-        assert_eq!(buf.dequeue(), None);
+        assert_eq!(buf.capacity(), 4);
+        assert_eq!(buf.head(), 0);
+        assert!(!buf.is_wrapped());
+        assert_eq!(buf.iter().next(), None);
     }
 
     #[test]
-    fn enqueue_dequeue_fifo() {
+    fn enqueue_fifo() {
         let capacity = 10;
         let mut buf: RingBuffer<usize> = RingBuffer::new(capacity);
         for i in 0..capacity {
             buf.enqueue(i);
         }
-        for i in 0..capacity {
-            assert_eq!(buf.dequeue(), Some(i));
-        }
-        assert_eq!(buf.dequeue(), None);
+        // Filling the buffer does not wrap it.
+        assert!(!buf.is_wrapped());
+        assert_eq!(buf.head(), capacity);
+        assert_eq!(buf.iter().cloned().collect::<Vec<usize>>(), (0..capacity).collect::<Vec<_>>());
     }
 
     #[test]
-    fn enqueue_dequeue_overwrite() {
+    fn enqueue_overwrite() {
         let mut buf: RingBuffer<usize> = RingBuffer::new(10);
         for x in 0..15 {
             buf.enqueue(x);
         }
         assert_eq!(buf.len(), 10);
-        let mut contents = Vec::new();
-        while let Some(x) = buf.dequeue() {
-            contents.push(x);
+        assert!(buf.is_wrapped());
+        assert_eq!(buf.head(), 5);
+        assert_eq!(buf.iter().cloned().collect::<Vec<usize>>(),
+                   vec![5, 6, 7, 8, 9, 10, 11, 12, 13, 14]);
+
+        // Items stay where they were written.
+        assert_eq!(buf.as_slice(), &[10, 11, 12, 13, 14, 5, 6, 7, 8, 9]);
+
+        // Wrapping again at the end of the array still leaves the oldest at `head`.
+        for x in 15..20 {
+            buf.enqueue(x);
         }
-        assert_eq!(contents, vec![5, 6, 7, 8, 9, 10, 11, 12, 13, 14]);
+        assert_eq!(buf.head(), 10);
+        assert_eq!(buf.iter().cloned().collect::<Vec<usize>>(), (10..20).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn clear() {
+        let mut buf: RingBuffer<usize> = RingBuffer::new(3);
+        for x in 0..5 {
+            buf.enqueue(x);
+        }
+        buf.clear();
         assert_eq!(buf.len(), 0);
+        assert!(!buf.is_wrapped());
+        buf.enqueue(5);
+        assert_eq!(buf.iter().cloned().collect::<Vec<usize>>(), vec![5]);
     }
 
     #[test]
@@ -114,8 +171,19 @@ mod test_ring_buffer {
             buf.enqueue(s);
         }
         let slice = buf.as_slice();
+        assert_eq!(slice.len(), strings.len());
         for (&actual, &expected) in slice.iter().zip(strings.iter()) {
             assert_eq!(actual, expected);
         }
     }
+
+    #[test]
+    fn as_slice_after_wrapping() {
+        // Used to panic once the underlying deque wrapped around.
+        let mut buf: RingBuffer<i32> = RingBuffer::new(3);
+        for x in 0..4 {
+            buf.enqueue(x);
+        }
+        assert_eq!(buf.as_slice(), &[3, 1, 2]);
+    }
 }
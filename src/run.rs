@@ -0,0 +1,894 @@
+//! Running instrumented modules in an embedded interpreter, so that traces can be
+//! collected without a JavaScript engine.
+
+use std::collections::HashSet;
+use std::io::{self, Write};
+use std::time::Instant;
+use parity_wasm::elements::Error;
+use wasmi::{self, Caller, Engine, Extern, ExternType, Global, Instance, Linker, Memory, Module,
+            Store, Table, Value};
+use wasmi::core::{Trap, ValueType, F32, F64};
+
+use counts::{self, CounterWidth, CALL_COUNTS, CALL_COUNTS_LEN};
+use coverage::{COVERAGE_COUNTERS, COVERAGE_LEN};
+use fuel::{FUEL_COUNTERS, FUEL_LEN};
+use module::{WasmModule, MEMORY};
+use trace::{decode, CallSite, TraceEvent, TraceValue};
+use tracer::{CLOCK_IMPORT, EXPOSE_TRACER, EXPOSE_TRACER_CAPACITY, EXPOSE_TRACER_HEAD,
+             EXPOSE_TRACER_LEN, EXPOSE_TRACER_WRAPPED, LOG_IMPORT, LOG_IMPORT_MODULE};
+
+static WASI: &str = "wasi_snapshot_preview1";
+
+#[derive(Debug)]
+/// Outcome of calling a function in an instrumented module.
+pub struct Execution {
+    /// Values returned by the function, or the trap that stopped it.
+    pub result: Result<Vec<Value>, wasmi::Error>,
+    /// Log entries recorded during the call, `ENTRY_WORDS` words each, oldest first.
+    pub entries: Vec<i32>,
+    /// Number of words the module's tracer buffer holds, if it has one that
+    /// exports `__expose_tracer_capacity`.
+    pub capacity: Option<usize>,
+    /// Whether the tracer buffer wrapped around, dropping the oldest entries.
+    pub wrapped: bool,
+    /// Coverage counters, if the module was instrumented for coverage.
+    pub counters: Vec<i32>,
+    /// Call counters, if the module was instrumented to count calls.
+    pub call_counts: Vec<u64>,
+    /// Instruction counters, if the module was instrumented to count instructions.
+    pub instruction_counts: Vec<u64>,
+}
+
+#[derive(Default)]
+/// Contents of a tracer buffer in linear memory.
+struct Buffer {
+    /// Words in the buffer, oldest first.
+    words: Vec<i32>,
+    capacity: Option<usize>,
+    wrapped: bool,
+}
+
+/// State shared with host functions.
+struct Host {
+    /// Entries received through `wasm_trace.log`.
+    entries: Vec<i32>,
+    /// Origin for `wasm_trace.now`.
+    started: Instant,
+}
+
+/// Calls the exported function `func` in `module` with `args`, parsed according to
+/// its parameter types, and collects the trace.
+/// Imports are satisfied with stubs: `wasm_trace.log` records entries,
+/// `wasm_trace.now` returns nanoseconds since the module was loaded, WASI
+/// `fd_write` and `proc_exit` behave as expected, `env.abort` traps, and any other
+/// function returns zeroes. Imported memories, tables and globals are created empty.
+pub fn run(module: &WasmModule, func: &str, args: &[String]) -> Result<Execution, Error> {
+    let bytes = module.clone().into_bytes()?;
+    let counter_width = counts::counted(module).map(|(width, _)| width).unwrap_or_default();
+    let engine = Engine::default();
+    let module = Module::new(&engine, &bytes[..]).map_err(interpreter_error)?;
+    let host = Host {
+        entries: vec![],
+        started: Instant::now(),
+    };
+    let mut store = Store::new(&engine, host);
+    let mut linker = Linker::new(&engine);
+    define_imports(&mut linker, &mut store, &module)?;
+
+    let instance = linker.instantiate(&mut store, &module)
+        .and_then(|instance| instance.start(&mut store))
+        .map_err(interpreter_error)?;
+
+    let callee = instance.get_func(&store, func)
+        .ok_or_else(|| Error::HeapOther(format!("No exported function {}", func)))?;
+    let ty = callee.ty(&store);
+    if ty.params().len() != args.len() {
+        return Err(Error::HeapOther(format!("{} takes {} arguments, but {} were given",
+                                            func,
+                                            ty.params().len(),
+                                            args.len())));
+    }
+    let params = ty.params()
+        .iter()
+        .zip(args)
+        .map(|(&ty, arg)| parse_value(ty, arg))
+        .collect::<Result<Vec<Value>, Error>>()?;
+    let mut results = ty.results().iter().map(|&ty| Value::default(ty)).collect::<Vec<_>>();
+    let result = callee.call(&mut store, &params, &mut results).map(|()| results);
+
+    let mut entries = store.data().entries.clone();
+    let buffer = read_buffer(&instance, &mut store)?;
+    entries.extend(buffer.words);
+
+    let counters = match (call_export(&instance, &mut store, COVERAGE_COUNTERS)?,
+                          call_export(&instance, &mut store, COVERAGE_LEN)?) {
+        (Some(ptr), Some(len)) => read_words(&instance, &store, ptr, len)?,
+        _ => vec![],
+    };
+
+    let call_counts = match (call_export(&instance, &mut store, CALL_COUNTS)?,
+                             call_export(&instance, &mut store, CALL_COUNTS_LEN)?) {
+        (Some(ptr), Some(len)) => {
+            match counter_width {
+                CounterWidth::I32 => {
+                    read_words(&instance, &store, ptr, len)?
+                        .into_iter()
+                        .map(|count| count as u32 as u64)
+                        .collect()
+                }
+                CounterWidth::I64 => read_u64s(&instance, &store, ptr, len)?,
+            }
+        }
+        _ => vec![],
+    };
+
+    let instruction_counts = match (call_export(&instance, &mut store, FUEL_COUNTERS)?,
+                                    call_export(&instance, &mut store, FUEL_LEN)?) {
+        (Some(ptr), Some(len)) => read_u64s(&instance, &store, ptr, len)?,
+        _ => vec![],
+    };
+
+    Ok(Execution {
+        result,
+        entries,
+        capacity: buffer.capacity,
+        wrapped: buffer.wrapped,
+        counters,
+        call_counts,
+        instruction_counts,
+    })
+}
+
+/// Calls the exported function `name`, which takes no arguments and returns an `i32`,
+/// if the module has one.
+fn call_export(instance: &Instance,
+               store: &mut Store<Host>,
+               name: &str)
+               -> Result<Option<i32>, Error> {
+    let func = match instance.get_func(&*store, name) {
+        Some(func) => func,
+        None => return Ok(None),
+    };
+    let mut result = [Value::I32(0)];
+    func.call(store, &[], &mut result).map_err(interpreter_error)?;
+    Ok(result[0].i32())
+}
+
+/// Reads `len` words at address `ptr` in the exported memory.
+fn read_words(instance: &Instance,
+              store: &Store<Host>,
+              ptr: i32,
+              len: i32)
+              -> Result<Vec<i32>, Error> {
+    let (ptr, len) = (ptr as u32 as usize, len as u32 as usize);
+    let memory = instance.get_memory(store, MEMORY)
+        .ok_or(Error::Other("The module does not export its memory"))?;
+    let bytes = memory.data(store)
+        .get(ptr..ptr + len * 4)
+        .ok_or(Error::Other("The tracer buffer or counters are out of bounds"))?;
+    Ok(bytes.chunks(4)
+        .map(|word| i32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+        .collect())
+}
+
+/// Reads `len` little-endian `u64`s at address `ptr` in the exported memory.
+fn read_u64s(instance: &Instance,
+             store: &Store<Host>,
+             ptr: i32,
+             len: i32)
+             -> Result<Vec<u64>, Error> {
+    Ok(read_words(instance, store, ptr, len.wrapping_mul(2))?
+        .chunks(2)
+        .map(|words| words[0] as u32 as u64 | (words[1] as u32 as u64) << 32)
+        .collect())
+}
+
+/// Reads the buffer of a module with an exported tracer, if it has one.
+/// Tracers that do not export their head are taken to keep entries in order.
+fn read_buffer(instance: &Instance, store: &mut Store<Host>) -> Result<Buffer, Error> {
+    let mut call = |name: &str| call_export(instance, store, name);
+    let (ptr, len) = match (call(EXPOSE_TRACER)?, call(EXPOSE_TRACER_LEN)?) {
+        (Some(ptr), Some(len)) => (ptr, len),
+        _ => return Ok(Buffer::default()),
+    };
+    let capacity = call(EXPOSE_TRACER_CAPACITY)?.map(|capacity| capacity as u32 as usize);
+    let head = call(EXPOSE_TRACER_HEAD)?.map_or(0, |head| head as u32 as usize);
+    let wrapped = call(EXPOSE_TRACER_WRAPPED)? == Some(1);
+    let mut words = read_words(instance, store, ptr, len)?;
+    if wrapped {
+        if head > words.len() {
+            return Err(Error::Other("The tracer head is out of bounds"));
+        }
+        words.rotate_left(head);
+    }
+    Ok(Buffer { words, capacity, wrapped })
+}
+
+fn define_imports(linker: &mut Linker<Host>,
+                  store: &mut Store<Host>,
+                  module: &Module)
+                  -> Result<(), Error> {
+    linker.func_wrap(LOG_IMPORT_MODULE,
+                     LOG_IMPORT,
+                     |mut caller: Caller<Host>, kind: i32, id: i32, bits: i64| {
+                         let entries = &mut caller.data_mut().entries;
+                         entries.extend_from_slice(&[kind, id, bits as i32, (bits >> 32) as i32]);
+                     })
+        .and_then(|linker| {
+            linker.func_wrap(LOG_IMPORT_MODULE, CLOCK_IMPORT, |caller: Caller<Host>| {
+                caller.data().started.elapsed().as_nanos() as i64
+            })
+        })
+        .and_then(|linker| linker.func_wrap(WASI, "fd_write", fd_write))
+        .and_then(|linker| {
+            linker.func_wrap(WASI, "proc_exit", |status: i32| -> Result<(), Trap> {
+                Err(Trap::i32_exit(status))
+            })
+        })
+        .map_err(interpreter_error)?;
+
+    let mut defined = [(LOG_IMPORT_MODULE, LOG_IMPORT),
+                       (LOG_IMPORT_MODULE, CLOCK_IMPORT),
+                       (WASI, "fd_write"),
+                       (WASI, "proc_exit")]
+        .iter()
+        .map(|&(module, name)| (module.to_owned(), name.to_owned()))
+        .collect::<HashSet<_>>();
+    for import in module.imports() {
+        let (module, name) = (import.module(), import.name());
+        if !defined.insert((module.to_owned(), name.to_owned())) {
+            continue;
+        }
+        let stub = match import.ty() {
+            ExternType::Func(ty) => {
+                let abort = module == "env" && name == "abort";
+                linker.func_new(module, name, ty.clone(), move |_, _, results| {
+                        if abort {
+                            return Err(Trap::new("abort"));
+                        }
+                        for result in results.iter_mut() {
+                            *result = Value::default(result.ty());
+                        }
+                        Ok(())
+                    })
+                    .map_err(interpreter_error)?;
+                continue;
+            }
+            ExternType::Memory(ty) => {
+                Memory::new(&mut *store, *ty).map(Extern::from).map_err(interpreter_error)?
+            }
+            ExternType::Table(ty) => {
+                Table::new(&mut *store, *ty, Value::default(ty.element()))
+                    .map(Extern::from)
+                    .map_err(interpreter_error)?
+            }
+            ExternType::Global(ty) => {
+                Extern::from(Global::new(&mut *store,
+                                         Value::default(ty.content()),
+                                         ty.mutability()))
+            }
+        };
+        linker.define(module, name, stub).map_err(interpreter_error)?;
+    }
+    Ok(())
+}
+
+/// Writes the buffers described by an array of `iovec`s to stdout or stderr.
+fn fd_write(mut caller: Caller<Host>,
+            fd: i32,
+            iovs: i32,
+            iovs_len: i32,
+            nwritten: i32)
+            -> Result<i32, Trap> {
+    const BADF: i32 = 8;
+    const FAULT: i32 = 21;
+
+    let memory = match caller.get_export(MEMORY).and_then(Extern::into_memory) {
+        Some(memory) => memory,
+        None => return Ok(FAULT),
+    };
+    let data = memory.data_mut(&mut caller);
+    let word = |data: &[u8], addr: usize| {
+        data.get(addr..addr + 4).map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]) as usize)
+    };
+
+    let mut out = Vec::new();
+    for i in 0..iovs_len as u32 as usize {
+        let iov = iovs as u32 as usize + i * 8;
+        let buf = word(data, iov).and_then(|ptr| {
+            word(data, iov + 4).and_then(|len| data.get(ptr..ptr + len))
+        });
+        match buf {
+            Some(buf) => out.extend_from_slice(buf),
+            None => return Ok(FAULT),
+        }
+    }
+    let written = match fd {
+        1 => io::stdout().write_all(&out),
+        2 => io::stderr().write_all(&out),
+        _ => return Ok(BADF),
+    };
+    if written.is_err() {
+        return Ok(BADF);
+    }
+    match data.get_mut(nwritten as u32 as usize..nwritten as u32 as usize + 4) {
+        Some(dest) => dest.copy_from_slice(&(out.len() as u32).to_le_bytes()),
+        None => return Ok(FAULT),
+    }
+    Ok(0)
+}
+
+/// Parses a command line argument as a value of type `ty`.
+/// Integers may be given signed or unsigned.
+fn parse_value(ty: ValueType, arg: &str) -> Result<Value, Error> {
+    let value = match ty {
+        ValueType::I32 => {
+            arg.parse::<i32>().ok().or_else(|| arg.parse::<u32>().ok().map(|v| v as i32))
+                .map(Value::I32)
+        }
+        ValueType::I64 => {
+            arg.parse::<i64>().ok().or_else(|| arg.parse::<u64>().ok().map(|v| v as i64))
+                .map(Value::I64)
+        }
+        ValueType::F32 => arg.parse::<f32>().ok().map(|v| Value::F32(F32::from(v))),
+        ValueType::F64 => arg.parse::<f64>().ok().map(|v| Value::F64(F64::from(v))),
+        ValueType::FuncRef | ValueType::ExternRef => None,
+    };
+    value.ok_or_else(|| Error::HeapOther(format!("Invalid {:?} argument {}", ty, arg)))
+}
+
+/// Formats a value returned by the interpreter the way the trace does.
+pub fn format_value(value: &Value) -> String {
+    match value {
+        Value::I32(v) => v.to_string(),
+        Value::I64(v) => v.to_string(),
+        Value::F32(v) => f32::from(*v).to_string(),
+        Value::F64(v) => f64::from(*v).to_string(),
+        other => format!("{:?}", other),
+    }
+}
+
+/// Writes the trace in the same format as `examples/js/tracer.js`, naming functions
+/// after `module`.
+pub fn write_trace<W: Write>(out: &mut W, entries: &[i32], module: &WasmModule) -> io::Result<()> {
+    let mut depth = 0;
+    for event in decode(entries, module) {
+        let name = event.name().map_or(event.id().to_string(), str::to_owned);
+        match event {
+            TraceEvent::Call { ref args, .. } if !args.is_empty() => {
+                let args = args.iter().map(TraceValue::to_string).collect::<Vec<String>>();
+                writeln!(out, "{} call {}({})", "  | ".repeat(depth), name, args.join(", "))?;
+                depth += 1;
+            }
+            TraceEvent::Call { .. } => {
+                writeln!(out, "{} call function {}", "  | ".repeat(depth), name)?;
+                depth += 1;
+            }
+            TraceEvent::Return { value, .. } => {
+                depth = depth.saturating_sub(1);
+                let value = value.map_or(String::new(), |v| format!(" {}", v));
+                writeln!(out, "{} return{} from {}", "  | ".repeat(depth), value, name)?;
+            }
+            // Imported functions are not traced themselves, so their call sites
+            // stand in for their calls and returns.
+            TraceEvent::CallSite { ref site, .. } if site.imported => {
+                writeln!(out,
+                         "{} call import {} from {}+{}",
+                         "  | ".repeat(depth),
+                         callee_label(site),
+                         name,
+                         site.offset)?;
+                depth += 1;
+            }
+            TraceEvent::CallSiteReturn { ref site, .. } if site.imported => {
+                depth = depth.saturating_sub(1);
+                writeln!(out,
+                         "{} return from import {}",
+                         "  | ".repeat(depth),
+                         callee_label(site))?;
+            }
+            TraceEvent::CallSite { ref site, .. } => {
+                writeln!(out,
+                         "{} {}+{} calls {}",
+                         "  | ".repeat(depth),
+                         name,
+                         site.offset,
+                         callee_label(site))?;
+            }
+            TraceEvent::CallSiteReturn { .. } => {}
+            TraceEvent::IndirectCall { table_index, ref site, traced, .. } => {
+                let target = match site.callee {
+                    Some(_) => format!(" = {}", callee_label(site)),
+                    None => String::new(),
+                };
+                writeln!(out,
+                         "{} {}+{} calls table[{}]{}{}",
+                         "  | ".repeat(depth),
+                         name,
+                         site.offset,
+                         table_index,
+                         target,
+                         if traced { "" } else { " (not traced)" })?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Name or index of the function called at `site`.
+fn callee_label(site: &CallSite) -> String {
+    match (site.callee, &site.callee_name) {
+        (_, Some(name)) => name.clone(),
+        (Some(callee), None) => callee.to_string(),
+        (None, None) => "a function through a table".to_owned(),
+    }
+}
+
+fn interpreter_error<E: ToString>(e: E) -> Error {
+    Error::HeapOther(e.to_string())
+}
+
+#[cfg(test)]
+mod test_run {
+    use module::WasmModule;
+    use options::{Backend, Clock, InstrumentOptions, Scope};
+    use tracer::{EntryKind, ENTRY_WORDS};
+    use trace::decode;
+    use wasmi::Value;
+    use super::{run, write_trace};
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn traced(backend: Backend) -> (WasmModule, Vec<i32>) {
+        let mut module = WasmModule::from_file("./tests/function-names.wasm").unwrap();
+        let options = InstrumentOptions {
+            log_arguments: true,
+            backend,
+            ..Default::default()
+        };
+        module.instrument_module_with(&options).unwrap();
+
+        let execution = run(&module, "_Z3addii", &args(&["3", "4"])).unwrap();
+        match execution.result.unwrap()[..] {
+            [Value::I32(7)] => {}
+            ref other => panic!("Unexpected result {:?}", other),
+        }
+        (module, execution.entries)
+    }
+
+    #[test]
+    fn run_with_injected_runtime() {
+        let (module, entries) = traced(Backend::Buffer);
+        let id = 0;
+        assert_eq!(entries,
+                   vec![EntryKind::FunctionCall as i32, id, 0, 0,
+                        EntryKind::ArgumentI32 as i32, id, 3, 0,
+                        EntryKind::ArgumentI32 as i32, id, 4, 0,
+                        EntryKind::FunctionReturnI32 as i32, id, 7, 0]);
+
+        let mut out = Vec::new();
+        write_trace(&mut out, &entries, &module).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   " call _Z3addii(3, 4)\n return 7 from _Z3addii\n");
+    }
+
+    #[test]
+    fn run_with_host_import() {
+        // The import takes index 0, so `_Z3addii` moves to 1.
+        let (_, entries) = traced(Backend::HostImport);
+        assert_eq!(entries.len(), 16);
+        assert_eq!(&entries[..2], &[EntryKind::FunctionCall as i32, 1]);
+    }
+
+    #[test]
+    fn run_with_capacity() {
+        let mut module = WasmModule::from_file("./tests/function-names.wasm").unwrap();
+        let options = InstrumentOptions {
+            capacity: 3,
+            ..Default::default()
+        };
+        module.instrument_module_with(&options).unwrap();
+
+        let execution = run(&module, "_Z3addii", &args(&["3", "4"])).unwrap();
+        assert_eq!(execution.capacity, Some(3 * ENTRY_WORDS));
+        assert!(!execution.wrapped);
+
+        // With arguments there are four entries, so the call is dropped.
+        let (_, entries) = traced(Backend::Buffer);
+        let mut module = WasmModule::from_file("./tests/function-names.wasm").unwrap();
+        let options = InstrumentOptions {
+            log_arguments: true,
+            capacity: 3,
+            ..Default::default()
+        };
+        module.instrument_module_with(&options).unwrap();
+        let execution = run(&module, "_Z3addii", &args(&["3", "4"])).unwrap();
+        assert!(execution.wrapped);
+        assert_eq!(execution.entries, &entries[ENTRY_WORDS..]);
+    }
+
+    #[test]
+    fn read_wrapped_buffer() {
+        use parity_wasm::builder;
+        use parity_wasm::elements::{Instruction, Instructions};
+
+        // `calls` calls `leaf` three times, logging eight entries.
+        let leaf = builder::function()
+            .signature().build()
+            .body().with_instructions(Instructions::new(vec![Instruction::End])).build()
+            .build();
+        let calls = builder::function()
+            .signature().build()
+            .body()
+            .with_instructions(Instructions::new(vec![Instruction::Call(0),
+                                                      Instruction::Call(0),
+                                                      Instruction::Call(0),
+                                                      Instruction::End]))
+            .build()
+            .build();
+        let mut module = builder::module();
+        module.push_function(leaf);
+        module.push_function(calls);
+        let module = module.export().field("calls").internal().func(1).build().build();
+        let mut module = WasmModule::from_module(module);
+        let options = InstrumentOptions {
+            scope: Scope::All,
+            capacity: 5,
+            ..Default::default()
+        };
+        module.instrument_module_with(&options).unwrap();
+
+        // The last five entries are read back in order.
+        let execution = run(&module, "calls", &[]).unwrap();
+        assert!(execution.wrapped);
+        let entries = execution.entries
+            .chunks(ENTRY_WORDS)
+            .map(|entry| (entry[0], entry[1]))
+            .collect::<Vec<_>>();
+        let (call, ret) = (EntryKind::FunctionCall as i32, EntryKind::FunctionReturnVoid as i32);
+        assert_eq!(entries, [(call, 0), (ret, 0), (call, 0), (ret, 0), (ret, 1)]);
+    }
+
+    #[test]
+    fn run_with_indirect_calls() {
+        use parity_wasm::builder;
+        use parity_wasm::elements::{Instruction, Instructions};
+        use selection::Matcher;
+        use trace::{CallSite, TraceEvent};
+
+        // `dispatch` calls slot 0 (`traced`) or slot 1 (`hidden`) of the table.
+        let empty = || {
+            builder::function()
+                .signature().build()
+                .body().with_instructions(Instructions::new(vec![Instruction::End])).build()
+                .build()
+        };
+        let dispatch = builder::function()
+            .signature().param().i32().build()
+            .body()
+            .with_instructions(Instructions::new(vec![Instruction::GetLocal(0),
+                                                      Instruction::CallIndirect(0, 0),
+                                                      Instruction::End]))
+            .build()
+            .build();
+        let mut module = builder::module();
+        module.push_function(empty());
+        module.push_function(empty());
+        module.push_function(dispatch);
+        let module = module
+            .table().with_min(2).with_element(0, vec![0, 1]).build()
+            .export().field("dispatch").internal().func(2).build()
+            .build();
+        let mut module = WasmModule::from_module(module);
+        let mut options = InstrumentOptions {
+            scope: Scope::All,
+            indirect_calls: true,
+            ..Default::default()
+        };
+        options.selection.exclude(Matcher::Indices(1..2));
+        module.instrument_module_with(&options).unwrap();
+
+        let indirect_call = |slot: &str| {
+            let entries = run(&module, "dispatch", &args(&[slot])).unwrap().entries;
+            decode(&entries, &module)
+                .into_iter()
+                .find(|event| matches!(event, TraceEvent::IndirectCall { .. }))
+                .unwrap()
+        };
+        let site = |callee: usize| {
+            CallSite { offset: 1, callee: Some(callee), callee_name: None, imported: false }
+        };
+        assert_eq!(indirect_call("0"),
+                   TraceEvent::IndirectCall {
+                       id: 2,
+                       name: Some("dispatch".to_owned()),
+                       table_index: 0,
+                       site: site(0),
+                       traced: true,
+                   });
+        assert_eq!(indirect_call("1"),
+                   TraceEvent::IndirectCall {
+                       id: 2,
+                       name: Some("dispatch".to_owned()),
+                       table_index: 1,
+                       site: site(1),
+                       traced: false,
+                   });
+    }
+
+    #[test]
+    fn run_with_coverage() {
+        use parity_wasm::builder;
+        use parity_wasm::elements::{BlockType, Instruction, Instructions, ValueType};
+
+        // `pick` returns 1 or 2 from either arm of an `if`.
+        let pick = builder::function()
+            .signature().param().i32().return_type().i32().build()
+            .body()
+            .with_instructions(Instructions::new(vec![
+                Instruction::GetLocal(0),
+                Instruction::If(BlockType::Value(ValueType::I32)),
+                Instruction::I32Const(1),
+                Instruction::Else,
+                Instruction::I32Const(2),
+                Instruction::End,
+                Instruction::End,
+            ]))
+            .build()
+            .build();
+        let mut module = builder::module();
+        module.push_function(pick);
+        let module = module.export().field("pick").internal().func(0).build().build();
+        let mut module = WasmModule::from_module(module);
+        module.instrument_coverage(&InstrumentOptions::default()).unwrap();
+
+        // Entry, `if` and `else`, in order.
+        let execution = run(&module, "pick", &args(&["1"])).unwrap();
+        assert_eq!(execution.result.unwrap()[0].i32(), Some(1));
+        assert_eq!(execution.counters, [1, 1, 0]);
+        assert!(execution.entries.is_empty());
+        let execution = run(&module, "pick", &args(&["0"])).unwrap();
+        assert_eq!(execution.counters, [1, 0, 1]);
+
+        // `check` traps from an `if` without an `else`, which is given one, so the
+        // condition never holding leaves the `if` uncovered.
+        let check = builder::function()
+            .signature().param().i32().build()
+            .body()
+            .with_instructions(Instructions::new(vec![
+                Instruction::GetLocal(0),
+                Instruction::If(BlockType::NoResult),
+                Instruction::Unreachable,
+                Instruction::End,
+                Instruction::End,
+            ]))
+            .build()
+            .build();
+        let mut module = builder::module();
+        module.push_function(check);
+        let module = module.export().field("check").internal().func(0).build().build();
+        let mut module = WasmModule::from_module(module);
+        module.instrument_coverage(&InstrumentOptions::default()).unwrap();
+
+        // Entry, `if` and the added `else`, in order.
+        let execution = run(&module, "check", &args(&["0"])).unwrap();
+        assert!(execution.result.is_ok());
+        assert_eq!(execution.counters, [1, 0, 1]);
+    }
+
+    #[test]
+    fn run_with_call_counts() {
+        use parity_wasm::builder;
+        use parity_wasm::elements::{Instruction, Instructions};
+        use counts::{histogram, CounterWidth};
+
+        // `twice` calls `once` twice.
+        let once = builder::function()
+            .signature().build()
+            .body().build()
+            .build();
+        let twice = builder::function()
+            .signature().build()
+            .body()
+            .with_instructions(Instructions::new(vec![
+                Instruction::Call(0),
+                Instruction::Call(0),
+                Instruction::End,
+            ]))
+            .build()
+            .build();
+        let mut module = builder::module();
+        module.push_function(once);
+        module.push_function(twice);
+        let module = module.export().field("twice").internal().func(1).build().build();
+        let mut module = WasmModule::from_module(module);
+        let options = InstrumentOptions {
+            scope: Scope::All,
+            ..Default::default()
+        };
+        let functions = module.instrument_call_counts(&options, CounterWidth::I64).unwrap();
+        assert_eq!(functions, [0, 1]);
+
+        let execution = run(&module, "twice", &[]).unwrap();
+        assert_eq!(execution.call_counts, [2, 1]);
+        assert!(execution.entries.is_empty());
+        let counts = histogram(&module, &execution.call_counts).unwrap();
+        assert_eq!((counts[0].id, counts[0].calls), (0, 2));
+    }
+
+    #[test]
+    fn run_with_heap_base() {
+        use parity_wasm::builder;
+        use parity_wasm::elements::{Instruction, Instructions};
+        use counts::CounterWidth;
+
+        // `alloc` writes to the first word of the heap, as an allocator starting at
+        // `__heap_base` would, and reads it back.
+        let alloc = builder::function()
+            .signature().return_type().i32().build()
+            .body()
+            .with_instructions(Instructions::new(vec![
+                Instruction::GetGlobal(0),
+                Instruction::I32Const(42),
+                Instruction::I32Store(2, 0),
+                Instruction::GetGlobal(0),
+                Instruction::I32Load(2, 0),
+                Instruction::End,
+            ]))
+            .build()
+            .build();
+        let mut module = builder::module();
+        module.push_function(alloc);
+        let module = module.memory().with_min(1).build()
+            .global().value_type().i32().init_expr(Instruction::I32Const(1024)).build()
+            .export().field("__heap_base").internal().global(0).build()
+            .export().field("alloc").internal().func(0).build()
+            .build();
+        let mut module = WasmModule::from_module(module);
+        let options = InstrumentOptions {
+            scope: Scope::All,
+            ..Default::default()
+        };
+        module.instrument_call_counts(&options, CounterWidth::I32).unwrap();
+
+        // The counter is at the old `__heap_base`, out of the allocator's way.
+        let execution = run(&module, "alloc", &[]).unwrap();
+        match execution.result.unwrap()[..] {
+            [Value::I32(42)] => {}
+            ref other => panic!("Unexpected result {:?}", other),
+        }
+        assert_eq!(execution.call_counts, [1]);
+    }
+
+    #[test]
+    fn run_with_timestamps() {
+        let mut module = WasmModule::from_file("./tests/function-names.wasm").unwrap();
+        let options = InstrumentOptions {
+            timestamps: Some(Clock::Counter),
+            ..Default::default()
+        };
+        module.instrument_module_with(&options).unwrap();
+        let entries = run(&module, "_Z3addii", &args(&["3", "4"])).unwrap().entries;
+        let id = 0;
+        assert_eq!(entries,
+                   vec![EntryKind::FunctionCall as i32, id, 0, 0,
+                        EntryKind::Timestamp as i32, id, 1, 0,
+                        EntryKind::FunctionReturnI32 as i32, id, 7, 0,
+                        EntryKind::Timestamp as i32, id, 2, 0]);
+
+        // The host clock moves forward.
+        let mut module = WasmModule::from_file("./tests/function-names.wasm").unwrap();
+        let options = InstrumentOptions {
+            timestamps: Some(Clock::Host),
+            ..Default::default()
+        };
+        module.instrument_module_with(&options).unwrap();
+        let entries = run(&module, "_Z3addii", &args(&["3", "4"])).unwrap().entries;
+        let time = |entry: &[i32]| (entry[2] as u32 as i64) | ((entry[3] as i64) << 32);
+        assert_eq!(entries[4], EntryKind::Timestamp as i32);
+        assert_eq!(entries[12], EntryKind::Timestamp as i32);
+        assert!(time(&entries[4..8]) <= time(&entries[12..16]));
+    }
+
+    #[test]
+    fn run_with_instruction_counts() {
+        use parity_wasm::builder;
+        use parity_wasm::elements::{Instruction, Instructions};
+        use trace::{call_tree, function_times};
+
+        // `caller` calls `callee` twice; both run three instructions a call.
+        let callee = builder::function()
+            .signature().build()
+            .body()
+            .with_instructions(Instructions::new(vec![
+                Instruction::I32Const(1),
+                Instruction::Drop,
+                Instruction::End,
+            ]))
+            .build()
+            .build();
+        let caller = builder::function()
+            .signature().build()
+            .body()
+            .with_instructions(Instructions::new(vec![
+                Instruction::Call(0),
+                Instruction::Call(0),
+                Instruction::End,
+            ]))
+            .build()
+            .build();
+        let mut module = builder::module();
+        module.push_function(callee);
+        module.push_function(caller);
+        let module = module.export().field("caller").internal().func(1).build().build();
+        let module = WasmModule::from_module(module);
+
+        let mut counted = module.clone();
+        counted.instrument_instruction_counts(&Default::default()).unwrap();
+        let execution = run(&counted, "caller", &[]).unwrap();
+        assert_eq!(execution.instruction_counts, [3]);
+        assert!(execution.entries.is_empty());
+
+        // Untraced callees count towards their callers.
+        let times = |scope| {
+            let mut module = module.clone();
+            let options = InstrumentOptions {
+                scope,
+                timestamps: Some(Clock::Instructions),
+                ..Default::default()
+            };
+            module.instrument_module_with(&options).unwrap();
+            let execution = run(&module, "caller", &[]).unwrap();
+            assert_eq!(execution.instruction_counts, [6, 3]);
+            let roots = call_tree(&decode(&execution.entries, &module));
+            function_times(&roots)
+                .into_iter()
+                .map(|time| (time.id, time.calls, time.inclusive, time.exclusive))
+                .collect::<Vec<(usize, u64, i64, i64)>>()
+        };
+        assert_eq!(times(Scope::Exported), [(1, 1, 9, 9)]);
+        assert_eq!(times(Scope::All), [(0, 2, 6, 6), (1, 1, 9, 3)]);
+    }
+
+    #[test]
+    fn stub_imports() {
+        // `_Z2hiv` calls the imported `printf`, which is stubbed out.
+        let mut module = WasmModule::from_file("./tests/imports.wasm").unwrap();
+        module.instrument_module().unwrap();
+        let execution = run(&module, "_Z2hiv", &[]).unwrap();
+        assert!(execution.result.is_ok());
+        assert_eq!(execution.entries.len(), 8);
+
+        assert!(run(&module, "_Z2hiv", &args(&["1"])).is_err());
+        assert!(run(&module, "missing", &[]).is_err());
+    }
+
+    #[test]
+    fn run_with_traced_imports() {
+        // The call to `printf` is logged by its wrapper, under the import's index.
+        let mut module = WasmModule::from_file("./tests/imports.wasm").unwrap();
+        let options = InstrumentOptions {
+            trace_imports: true,
+            ..Default::default()
+        };
+        module.instrument_module_with(&options).unwrap();
+        let execution = run(&module, "_Z2hiv", &[]).unwrap();
+        let events = decode(&execution.entries, &module);
+        let names = events.iter().map(|event| event.name().unwrap()).collect::<Vec<&str>>();
+        assert_eq!(names, ["_Z2hiv", "printf", "printf", "_Z2hiv"]);
+
+        // Call sites of wrapped imports are left to the wrapper, so the call is not
+        // logged twice.
+        let mut module = WasmModule::from_file("./tests/imports.wasm").unwrap();
+        let options = InstrumentOptions {
+            trace_imports: true,
+            call_sites: true,
+            ..Default::default()
+        };
+        module.instrument_module_with(&options).unwrap();
+        let execution = run(&module, "_Z2hiv", &[]).unwrap();
+        assert_eq!(decode(&execution.entries, &module), events);
+    }
+}
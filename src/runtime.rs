@@ -0,0 +1,266 @@
+//! Tracer runtime written directly in WebAssembly, for modules that were not
+//! built with `tracer_bootstrap!()`.
+//!
+//! The runtime keeps the same ABI and layout as the bootstrapped `Tracer`:
+//! `__log_call` records an entry in a ring of `capacity` entries, and the other
+//! exports describe the ring in `i32` words. `__expose_tracer` is the start of
+//! the ring, `__expose_tracer_len` the number of words in use,
+//! `__expose_tracer_capacity` the size of the ring, `__expose_tracer_head` the
+//! index the next entry is written to and `__expose_tracer_wrapped` whether
+//! older entries have been overwritten, in which case the oldest is at the head.
+//! `__tracer_enable`, `__tracer_disable` and `__tracer_clear` control logging.
+//!
+//! The ring lives in a region of linear memory set aside by
+//! `WasmModule::reserve_memory`, so that the module's own data is left alone.
+
+use parity_wasm::elements::*;
+
+use module::WasmModule;
+use tracer::{ENTRY_WORDS, EXPOSE_TRACER, EXPOSE_TRACER_CAPACITY, EXPOSE_TRACER_HEAD,
+             EXPOSE_TRACER_LEN, EXPOSE_TRACER_WRAPPED, LOG_CALL, TRACER_CLEAR, TRACER_DISABLE,
+             TRACER_ENABLE};
+
+const ENTRY_BYTES: u32 = ENTRY_WORDS as u32 * 4;
+
+/// Adds the tracer's globals, memory region and exported functions to `module`,
+/// keeping the last `capacity` entries, and returns the index of `__log_call` in
+/// the function index space.
+pub fn inject(module: &mut WasmModule, capacity: usize) -> Result<usize, Error> {
+    // The ring must fit in a 32-bit address space.
+    let ring_bytes = match (capacity as u64).checked_mul(ENTRY_BYTES as u64) {
+        Some(bytes) if capacity > 0 && bytes <= u32::MAX as u64 / 2 => bytes as u32,
+        _ => return Err(Error::HeapOther(format!("Invalid tracer capacity {}", capacity))),
+    };
+    let base = module.reserve_memory(ring_bytes)?;
+
+    let i32_global = |value| {
+        GlobalEntry::new(GlobalType::new(ValueType::I32, true),
+                         InitExpr::new(vec![Instruction::I32Const(value), Instruction::End]))
+    };
+    // Slot the next entry is written to, up to `capacity`.
+    let head = module.push_global(i32_global(0));
+    // 1 once an entry has been overwritten, 0 before.
+    let wrapped = module.push_global(i32_global(0));
+    // 1 while entries are logged, 0 while the tracer is disabled.
+    let enabled = module.push_global(i32_global(1));
+
+    let capacity = capacity as i32;
+    let log_call = vec![
+        Instruction::GetGlobal(enabled),
+        Instruction::I32Eqz,
+        Instruction::If(BlockType::NoResult),
+        Instruction::Return,
+        Instruction::End,
+        // Start over once the ring is full:
+        // slot = head == capacity ? 0 : head, wrapped |= head == capacity
+        Instruction::GetGlobal(head),
+        Instruction::I32Const(capacity),
+        Instruction::I32Eq,
+        Instruction::TeeLocal(3),
+        Instruction::GetGlobal(wrapped),
+        Instruction::I32Or,
+        Instruction::SetGlobal(wrapped),
+        Instruction::GetGlobal(head),
+        Instruction::I32Const(1),
+        Instruction::GetLocal(3),
+        Instruction::I32Sub,
+        Instruction::I32Mul,
+        Instruction::TeeLocal(3),
+        Instruction::I32Const(1),
+        Instruction::I32Add,
+        Instruction::SetGlobal(head),
+        // Address of the slot for this entry.
+        Instruction::GetLocal(3),
+        Instruction::I32Const(ENTRY_BYTES as i32),
+        Instruction::I32Mul,
+        Instruction::I32Const(base as i32),
+        Instruction::I32Add,
+        Instruction::TeeLocal(3),
+        Instruction::GetLocal(0),
+        Instruction::I32Store(2, 0),
+        Instruction::GetLocal(3),
+        Instruction::GetLocal(1),
+        Instruction::I32Store(2, 4),
+        Instruction::GetLocal(3),
+        Instruction::GetLocal(2),
+        Instruction::I64Store(3, 8),
+        Instruction::End,
+    ];
+
+    let expose_tracer = vec![Instruction::I32Const(base as i32), Instruction::End];
+
+    // Number of `i32` words in use: the whole ring once it has wrapped.
+    let expose_tracer_len = vec![
+        Instruction::I32Const(capacity),
+        Instruction::GetGlobal(head),
+        Instruction::GetGlobal(wrapped),
+        Instruction::Select,
+        Instruction::I32Const(ENTRY_WORDS as i32),
+        Instruction::I32Mul,
+        Instruction::End,
+    ];
+
+    let expose_tracer_capacity = vec![
+        Instruction::I32Const(capacity * ENTRY_WORDS as i32),
+        Instruction::End,
+    ];
+
+    let expose_tracer_head = vec![
+        Instruction::GetGlobal(head),
+        Instruction::I32Const(ENTRY_WORDS as i32),
+        Instruction::I32Mul,
+        Instruction::End,
+    ];
+
+    let expose_tracer_wrapped = vec![Instruction::GetGlobal(wrapped), Instruction::End];
+
+    let set_globals = |values: &[(u32, i32)]| {
+        let mut code = Vec::new();
+        for &(global, value) in values {
+            code.push(Instruction::I32Const(value));
+            code.push(Instruction::SetGlobal(global));
+        }
+        code.push(Instruction::End);
+        code
+    };
+    let tracer_enable = set_globals(&[(enabled, 1)]);
+    let tracer_disable = set_globals(&[(enabled, 0)]);
+    let tracer_clear = set_globals(&[(head, 0), (wrapped, 0)]);
+
+    let log_call_ty = FunctionType::new(vec![ValueType::I32, ValueType::I32, ValueType::I64],
+                                        None);
+    let expose_ty = FunctionType::new(vec![], Some(ValueType::I32));
+    let control_ty = FunctionType::new(vec![], None);
+    let functions = vec![(LOG_CALL,
+                          log_call_ty,
+                          vec![Local::new(1, ValueType::I32)],
+                          log_call),
+                         (EXPOSE_TRACER, expose_ty.clone(), vec![], expose_tracer),
+                         (EXPOSE_TRACER_LEN, expose_ty.clone(), vec![], expose_tracer_len),
+                         (EXPOSE_TRACER_CAPACITY,
+                          expose_ty.clone(),
+                          vec![],
+                          expose_tracer_capacity),
+                         (EXPOSE_TRACER_HEAD, expose_ty.clone(), vec![], expose_tracer_head),
+                         (EXPOSE_TRACER_WRAPPED, expose_ty, vec![], expose_tracer_wrapped),
+                         (TRACER_ENABLE, control_ty.clone(), vec![], tracer_enable),
+                         (TRACER_DISABLE, control_ty.clone(), vec![], tracer_disable),
+                         (TRACER_CLEAR, control_ty, vec![], tracer_clear)];
+
+    let mut ids = Vec::new();
+    for (name, ty, locals, code) in functions {
+        let body = FuncBody::new(locals, Instructions::new(code));
+        let id = module.push_function(ty, body, Some(name));
+        module.push_export(ExportEntry::new(name.to_owned(), Internal::Function(id as u32)));
+        ids.push(id);
+    }
+
+    Ok(ids[0])
+}
+
+#[cfg(test)]
+mod test_runtime {
+    use parity_wasm::elements::*;
+    use parity_wasm::builder;
+    use module::{WasmModule, MEMORY};
+    use tracer::{EXPOSE_TRACER, EXPOSE_TRACER_CAPACITY, EXPOSE_TRACER_HEAD, EXPOSE_TRACER_LEN,
+                 EXPOSE_TRACER_WRAPPED, LOG_CALL, TRACER_CLEAR, TRACER_DISABLE, TRACER_ENABLE};
+    use super::inject;
+
+    fn exported_function(module: &WasmModule, name: &str) -> Option<u32> {
+        module.exports().iter().find(|export| export.field() == name).and_then(|export| {
+            match export.internal() {
+                Internal::Function(id) => Some(*id),
+                _ => None,
+            }
+        })
+    }
+
+    #[test]
+    fn inject_into_module_without_tracer() {
+        let mut module = WasmModule::from_file("./tests/function-names.wasm").unwrap();
+        assert!(exported_function(&module, LOG_CALL).is_none());
+        module.instrument_module().unwrap();
+
+        // The runtime is appended after the existing functions.
+        assert_eq!(exported_function(&module, LOG_CALL), Some(4));
+        assert_eq!(exported_function(&module, EXPOSE_TRACER), Some(5));
+        assert_eq!(exported_function(&module, EXPOSE_TRACER_LEN), Some(6));
+        assert_eq!(exported_function(&module, EXPOSE_TRACER_CAPACITY), Some(7));
+        assert_eq!(exported_function(&module, EXPOSE_TRACER_HEAD), Some(8));
+        assert_eq!(exported_function(&module, EXPOSE_TRACER_WRAPPED), Some(9));
+        assert_eq!(exported_function(&module, TRACER_ENABLE), Some(10));
+        assert_eq!(exported_function(&module, TRACER_DISABLE), Some(11));
+        assert_eq!(exported_function(&module, TRACER_CLEAR), Some(12));
+        assert_eq!(module.get_function_name(4), Some(LOG_CALL));
+
+        // Existing functions log through the injected `__log_call`...
+        let body = &module.function_bodies()[0];
+        assert_eq!(body.code().elements()[3], Instruction::Call(4));
+        // ...but the runtime itself is not instrumented.
+        let log_call = &module.function_bodies()[4];
+        assert_eq!(log_call.code().elements()[0], Instruction::GetGlobal(2));
+
+        // Memory is exported so that the host can read the ring.
+        assert!(module.exports().iter().any(|export| {
+            export.field() == MEMORY && *export.internal() == Internal::Memory(0)
+        }));
+    }
+
+    #[test]
+    fn inject_with_capacity() {
+        let mut module = WasmModule::from_module(builder::module().build());
+        inject(&mut module, 16).unwrap();
+        assert_eq!(module.function_bodies()[3].code().elements(),
+                   &[Instruction::I32Const(64), Instruction::End]);
+
+        let mut module = WasmModule::from_module(builder::module().build());
+        assert!(inject(&mut module, 0).is_err());
+        assert!(inject(&mut module, 1 << 30).is_err());
+    }
+
+    #[test]
+    fn control_logging() {
+        use wasmi::{Engine, Linker, Module, Store, Value};
+
+        let mut module = WasmModule::from_module(builder::module().build());
+        inject(&mut module, 2).unwrap();
+        let bytes = module.into_bytes().unwrap();
+        let engine = Engine::default();
+        let wasm = Module::new(&engine, &bytes[..]).unwrap();
+        let mut store = Store::new(&engine, ());
+        let instance = Linker::new(&engine)
+            .instantiate(&mut store, &wasm)
+            .and_then(|instance| instance.start(&mut store))
+            .unwrap();
+
+        let mut call = |name: &str, params: &[Value]| -> Option<i32> {
+            let func = instance.get_func(&store, name).unwrap();
+            let mut results = func.ty(&store)
+                .results()
+                .iter()
+                .map(|&ty| Value::default(ty))
+                .collect::<Vec<Value>>();
+            func.call(&mut store, params, &mut results).unwrap();
+            results.first().and_then(Value::i32)
+        };
+        let log = [Value::I32(0), Value::I32(0), Value::I64(0)];
+
+        call(TRACER_DISABLE, &[]);
+        call(LOG_CALL, &log);
+        assert_eq!(call(EXPOSE_TRACER_LEN, &[]), Some(0));
+
+        call(TRACER_ENABLE, &[]);
+        for _ in 0..3 {
+            call(LOG_CALL, &log);
+        }
+        assert_eq!(call(EXPOSE_TRACER_WRAPPED, &[]), Some(1));
+
+        call(TRACER_CLEAR, &[]);
+        assert_eq!(call(EXPOSE_TRACER_LEN, &[]), Some(0));
+        assert_eq!(call(EXPOSE_TRACER_HEAD, &[]), Some(0));
+        assert_eq!(call(EXPOSE_TRACER_WRAPPED, &[]), Some(0));
+        call(LOG_CALL, &log);
+        assert_eq!(call(EXPOSE_TRACER_LEN, &[]), Some(4));
+    }
+}
@@ -0,0 +1,232 @@
+//! Choosing which functions to instrument.
+
+use std::fmt;
+use std::ops::Range;
+use std::str::FromStr;
+use glob::Pattern;
+use regex::Regex;
+use rustc_demangle::try_demangle;
+
+use module::WasmFunction;
+
+#[derive(Debug, Clone)]
+/// A predicate over functions in the function index space.
+pub enum Matcher {
+    /// Shell-style glob, matched against the raw or demangled function name.
+    /// Only Rust symbols are demangled: other mangled names, such as C++'s
+    /// `_Z3addii`, are only matched as they are.
+    Glob(Pattern),
+    /// Regular expression, matched against the raw or demangled function name, with
+    /// the same limit as `Glob`.
+    Regex(Regex),
+    /// Half-open range of indices in the function index space.
+    Indices(Range<usize>),
+    /// Functions listed in the export section.
+    Exported,
+    /// Functions listed in the import section.
+    Imported,
+}
+
+impl Matcher {
+    /// Checks whether `func` satisfies this predicate.
+    pub fn matches(&self, func: &WasmFunction) -> bool {
+        match self {
+            Matcher::Glob(pattern) => names(func).any(|name| pattern.matches(&name)),
+            Matcher::Regex(regex) => names(func).any(|name| regex.is_match(&name)),
+            Matcher::Indices(range) => range.contains(&func.id()),
+            Matcher::Exported => func.is_exported(),
+            Matcher::Imported => func.is_imported(),
+        }
+    }
+}
+
+/// The raw name of `func`, followed by its demangled name if it is a mangled Rust symbol.
+/// Demangled names omit the trailing hash, e.g. `core::fmt::write`.
+fn names(func: &WasmFunction) -> impl Iterator<Item = String> {
+    let raw = func.name();
+    let demangled = raw.and_then(|name| try_demangle(name).ok())
+        .map(|name| format!("{:#}", name));
+    raw.map(str::to_owned).into_iter().chain(demangled)
+}
+
+impl FromStr for Matcher {
+    type Err = String;
+
+    /// Parses a matcher specification:
+    ///
+    /// - `exported` or `imported`
+    /// - `index:N`, `index:A..B` or `index:A..`
+    /// - `regex:RE`
+    /// - `glob:GLOB`, or any other string, which is treated as a glob
+    fn from_str(spec: &str) -> Result<Self, String> {
+        if spec == "exported" {
+            return Ok(Matcher::Exported);
+        }
+        if spec == "imported" {
+            return Ok(Matcher::Imported);
+        }
+        if let Some(indices) = spec.strip_prefix("index:") {
+            return parse_range(indices).map(Matcher::Indices);
+        }
+        if let Some(regex) = spec.strip_prefix("regex:") {
+            return Regex::new(regex)
+                .map(Matcher::Regex)
+                .map_err(|e| format!("Invalid regex {}: {}", regex, e));
+        }
+        let glob = spec.strip_prefix("glob:").unwrap_or(spec);
+        Pattern::new(glob)
+            .map(Matcher::Glob)
+            .map_err(|e| format!("Invalid glob {}: {}", glob, e))
+    }
+}
+
+fn parse_range(spec: &str) -> Result<Range<usize>, String> {
+    let parse = |n: &str| {
+        n.parse::<usize>()
+            .map_err(|_| format!("Invalid function index {}", n))
+    };
+    match spec.find("..") {
+        Some(split) => {
+            let start = parse(&spec[..split])?;
+            let end = &spec[split + 2..];
+            let end = if end.is_empty() { usize::MAX } else { parse(end)? };
+            if start > end {
+                return Err(format!("Invalid function index range {}", spec));
+            }
+            Ok(start..end)
+        }
+        None => {
+            let id = parse(spec)?;
+            id.checked_add(1)
+                .map(|end| id..end)
+                .ok_or_else(|| format!("Invalid function index {}", spec))
+        }
+    }
+}
+
+impl fmt::Display for Matcher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Matcher::Glob(pattern) => write!(f, "glob:{}", pattern),
+            Matcher::Regex(regex) => write!(f, "regex:{}", regex),
+            Matcher::Indices(range) if range.end == usize::MAX => {
+                write!(f, "index:{}..", range.start)
+            }
+            Matcher::Indices(range) => write!(f, "index:{}..{}", range.start, range.end),
+            Matcher::Exported => write!(f, "exported"),
+            Matcher::Imported => write!(f, "imported"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+/// Include and exclude lists of matchers.
+/// A function is selected if it matches any include (or the include list is empty),
+/// and matches no exclude. Includes name functions explicitly, so they also select
+/// functions outside the `Scope` of the instrumentation.
+pub struct Selection {
+    include: Vec<Matcher>,
+    exclude: Vec<Matcher>,
+}
+
+impl Selection {
+    /// Creates a selection that accepts every function.
+    pub fn new() -> Self {
+        Selection::default()
+    }
+
+    /// Adds a matcher to the include list.
+    pub fn include(&mut self, matcher: Matcher) -> &mut Self {
+        self.include.push(matcher);
+        self
+    }
+
+    /// Adds a matcher to the exclude list.
+    pub fn exclude(&mut self, matcher: Matcher) -> &mut Self {
+        self.exclude.push(matcher);
+        self
+    }
+
+    /// Whether the include list has any matchers.
+    pub fn has_includes(&self) -> bool {
+        !self.include.is_empty()
+    }
+
+    /// Checks whether `func` is selected.
+    pub fn matches(&self, func: &WasmFunction) -> bool {
+        let included = self.include.is_empty() || self.include.iter().any(|m| m.matches(func));
+        included && !self.exclude.iter().any(|m| m.matches(func))
+    }
+}
+
+#[cfg(test)]
+mod test_selection {
+    use module::WasmModule;
+    use super::{Matcher, Selection};
+
+    fn selected(module: &WasmModule, selection: &Selection) -> Vec<usize> {
+        module
+            .functions()
+            .filter(|f| selection.matches(f))
+            .map(|f| f.id())
+            .collect()
+    }
+
+    #[test]
+    fn parse_matchers() {
+        let specs = ["exported", "imported", "index:3..7", "index:9..", "regex:^_Z[0-9]+add",
+                     "glob:core::*"];
+        for spec in specs.iter() {
+            assert_eq!(spec.parse::<Matcher>().unwrap().to_string(), *spec);
+        }
+        assert_eq!("index:4".parse::<Matcher>().unwrap().to_string(), "index:4..5");
+        assert_eq!("*add*".parse::<Matcher>().unwrap().to_string(), "glob:*add*");
+        assert!("index:x..2".parse::<Matcher>().is_err());
+        assert!("index:5..3".parse::<Matcher>().is_err());
+        assert!(format!("index:{}", usize::MAX).parse::<Matcher>().is_err());
+        assert!("regex:(".parse::<Matcher>().is_err());
+    }
+
+    #[test]
+    fn empty_selection_accepts_everything() {
+        let module = WasmModule::from_file("./tests/function-names.wasm").unwrap();
+        assert_eq!(selected(&module, &Selection::new()), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn include_and_exclude_compose() {
+        let module = WasmModule::from_file("./tests/function-names.wasm").unwrap();
+        let mut selection = Selection::new();
+        selection
+            .include("*add*".parse().unwrap())
+            .include("index:3".parse().unwrap())
+            .exclude("regex:add1".parse().unwrap());
+        assert_eq!(selected(&module, &selection), vec![0, 3]);
+    }
+
+    #[test]
+    fn import_and_export_status() {
+        let module = WasmModule::from_file("./tests/imports.wasm").unwrap();
+        let mut imported = Selection::new();
+        imported.include(Matcher::Imported);
+        assert_eq!(selected(&module, &imported), vec![0]);
+
+        let mut own = Selection::new();
+        own.exclude(Matcher::Imported);
+        assert_eq!(selected(&module, &own), vec![1]);
+    }
+
+    #[test]
+    fn demangled_names() {
+        let module = WasmModule::from_file("./tests/enter-exit-count.wasm").unwrap();
+        let mut selection = Selection::new();
+        selection.include("core::fmt::Arguments::*".parse().unwrap());
+        let names = module
+            .functions()
+            .filter(|f| selection.matches(f))
+            .map(|f| f.name().unwrap().to_owned())
+            .collect::<Vec<String>>();
+        assert!(!names.is_empty());
+        assert!(names.iter().all(|name| name.starts_with("_ZN4core3fmt9Arguments")));
+    }
+}
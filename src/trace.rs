@@ -0,0 +1,686 @@
+//! Decoding the tracer's log entries into calls and returns, and nesting them
+//! into call trees.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::mem;
+use std::slice;
+
+use module::WasmModule;
+use tracer::{EntryKind, ENTRY_WORDS};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// A logged argument or return value.
+pub enum TraceValue {
+    I32(i32),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+}
+
+impl TraceValue {
+    /// Reinterprets the bits logged for a value according to the entry kind.
+    /// Calls, void returns, timestamps, call sites and indirect calls carry no value.
+    pub fn decode(kind: EntryKind, low: i32, high: i32) -> Option<TraceValue> {
+        let bits = ((high as i64) << 32) | low as u32 as i64;
+        let value = match kind {
+            EntryKind::FunctionReturnI32 | EntryKind::ArgumentI32 => TraceValue::I32(low),
+            EntryKind::FunctionReturnI64 | EntryKind::ArgumentI64 => TraceValue::I64(bits),
+            EntryKind::FunctionReturnF32 | EntryKind::ArgumentF32 => {
+                TraceValue::F32(f32::from_bits(low as u32))
+            }
+            EntryKind::FunctionReturnF64 | EntryKind::ArgumentF64 => {
+                TraceValue::F64(f64::from_bits(bits as u64))
+            }
+            EntryKind::FunctionCall |
+            EntryKind::FunctionReturnVoid |
+            EntryKind::Timestamp |
+            EntryKind::CallSite |
+            EntryKind::CallSiteReturn |
+            EntryKind::IndirectCall => return None,
+        };
+        Some(value)
+    }
+}
+
+impl fmt::Display for TraceValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TraceValue::I32(v) => write!(f, "{}", v),
+            TraceValue::I64(v) => write!(f, "{}", v),
+            TraceValue::F32(v) => write!(f, "{}", v),
+            TraceValue::F64(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A function call or return, in the order they happened.
+pub enum TraceEvent {
+    /// Entry into function `id`, with its arguments if they were logged.
+    Call {
+        id: usize,
+        name: Option<String>,
+        args: Vec<TraceValue>,
+        time: Option<i64>,
+    },
+    /// Return from function `id`, with the value it returned, if any.
+    Return {
+        id: usize,
+        name: Option<String>,
+        value: Option<TraceValue>,
+        time: Option<i64>,
+    },
+    /// Function `id` is about to make the call at `site`.
+    CallSite {
+        id: usize,
+        name: Option<String>,
+        site: CallSite,
+        time: Option<i64>,
+    },
+    /// The call at `site`, made by function `id`, returned.
+    CallSiteReturn {
+        id: usize,
+        name: Option<String>,
+        site: CallSite,
+        time: Option<i64>,
+    },
+    /// Function `id` is about to call through slot `table_index` of the table,
+    /// at `site`. The callee is resolved through the element section, and is the
+    /// wrapped import if the slot holds an import's wrapper. If the callee is
+    /// `traced`, its own call follows.
+    IndirectCall {
+        id: usize,
+        name: Option<String>,
+        table_index: u32,
+        site: CallSite,
+        traced: bool,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A call instruction in a traced function.
+pub struct CallSite {
+    /// Index of the instruction in the caller's body, before instrumentation.
+    pub offset: usize,
+    /// Index of the called function, unless it was called through a table.
+    pub callee: Option<usize>,
+    /// Name of the called function, if known.
+    pub callee_name: Option<String>,
+    /// Whether the called function is imported, and so was not traced itself.
+    pub imported: bool,
+}
+
+impl TraceEvent {
+    /// Index of the function in the function index space. For call sites and
+    /// indirect calls, this is the caller.
+    pub fn id(&self) -> usize {
+        match self {
+            TraceEvent::Call { id, .. } |
+            TraceEvent::Return { id, .. } |
+            TraceEvent::CallSite { id, .. } |
+            TraceEvent::CallSiteReturn { id, .. } |
+            TraceEvent::IndirectCall { id, .. } => *id,
+        }
+    }
+
+    /// Name of the function, from the module the trace was decoded with.
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            TraceEvent::Call { name, .. } |
+            TraceEvent::Return { name, .. } |
+            TraceEvent::CallSite { name, .. } |
+            TraceEvent::CallSiteReturn { name, .. } |
+            TraceEvent::IndirectCall { name, .. } => name.as_deref(),
+        }
+    }
+
+    /// When the event happened, if timestamps were logged.
+    pub fn time(&self) -> Option<i64> {
+        match self {
+            TraceEvent::Call { time, .. } |
+            TraceEvent::Return { time, .. } |
+            TraceEvent::CallSite { time, .. } |
+            TraceEvent::CallSiteReturn { time, .. } => *time,
+            TraceEvent::IndirectCall { .. } => None,
+        }
+    }
+}
+
+/// Decodes a tracer buffer of `ENTRY_WORDS` words per entry into events, naming
+/// functions after `module`.
+/// Argument and timestamp entries are folded into the call or return they follow;
+/// those whose call or return was overwritten in the ring buffer are dropped, as are
+/// entries of unknown kinds and a trailing partial entry.
+pub fn decode(entries: &[i32], module: &WasmModule) -> Vec<TraceEvent> {
+    // Imported functions are usually missing from the name section.
+    let imported = module.imported_functions()
+        .map(|func| func.name().map(str::to_owned))
+        .collect::<Vec<Option<String>>>();
+    let name = |id: usize| {
+        module.get_function_name(id)
+            .map(str::to_owned)
+            .or_else(|| imported.get(id).cloned().flatten())
+    };
+    let logged_ids = module.logged_ids();
+    let imports_count = module.imported_functions_count();
+    let call_site = |low: i32, callee: Option<usize>| {
+        CallSite {
+            offset: low as u32 as usize,
+            callee,
+            callee_name: callee.and_then(name),
+            imported: callee.is_some_and(|callee| callee < imports_count),
+        }
+    };
+    let site = |low: i32, high: i32| {
+        call_site(low, if high == -1 { None } else { Some(high as u32 as usize) })
+    };
+
+    let mut events = Vec::new();
+    for entry in entries.chunks(ENTRY_WORDS) {
+        let (kind, id, low, high) = match *entry {
+            [kind, id, low, high] => (kind, id as u32 as usize, low, high),
+            _ => break,
+        };
+        let kind = match EntryKind::from_i32(kind) {
+            Some(kind) => kind,
+            None => continue,
+        };
+
+        if kind.is_argument() {
+            if let Some(TraceEvent::Call { args, time: None, .. }) = events.last_mut() {
+                args.extend(TraceValue::decode(kind, low, high));
+            }
+        } else if kind == EntryKind::Timestamp {
+            match events.last_mut() {
+                Some(TraceEvent::Call { id: last, time, .. }) |
+                Some(TraceEvent::Return { id: last, time, .. }) |
+                Some(TraceEvent::CallSite { id: last, time, .. }) |
+                Some(TraceEvent::CallSiteReturn { id: last, time, .. })
+                    if *last == id && time.is_none() => {
+                    *time = Some(((high as i64) << 32) | low as u32 as i64);
+                }
+                _ => {}
+            }
+        } else if kind == EntryKind::CallSite {
+            events.push(TraceEvent::CallSite {
+                            id,
+                            name: name(id),
+                            site: site(low, high),
+                            time: None,
+                        });
+        } else if kind == EntryKind::CallSiteReturn {
+            events.push(TraceEvent::CallSiteReturn {
+                            id,
+                            name: name(id),
+                            site: site(low, high),
+                            time: None,
+                        });
+        } else if kind == EntryKind::IndirectCall {
+            let table_index = high as u32;
+            let target = module.table_function(table_index);
+            let logged = target.and_then(|target| logged_ids.get(&target).cloned());
+            events.push(TraceEvent::IndirectCall {
+                            id,
+                            name: name(id),
+                            table_index,
+                            site: call_site(low, logged.or(target)),
+                            traced: logged.is_some(),
+                        });
+        } else if kind == EntryKind::FunctionCall {
+            events.push(TraceEvent::Call {
+                            id,
+                            name: name(id),
+                            args: vec![],
+                            time: None,
+                        });
+        } else {
+            events.push(TraceEvent::Return {
+                            id,
+                            name: name(id),
+                            value: TraceValue::decode(kind, low, high),
+                            time: None,
+                        });
+        }
+    }
+    events
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A function call, and the calls it made in turn.
+pub struct CallNode {
+    /// Index of the function in the function index space.
+    pub id: usize,
+    /// Name of the function, if known.
+    pub name: Option<String>,
+    /// Arguments, if they were logged and the call was recorded.
+    pub args: Vec<TraceValue>,
+    /// The returned value, if the function returned one.
+    pub value: Option<TraceValue>,
+    /// Whether the call was recorded. It is not when the ring buffer wrapped around
+    /// and overwrote it, but the return is still in the buffer.
+    pub entered: bool,
+    /// Whether the return was recorded. It is not when the trace ends mid-call,
+    /// e.g. because the call trapped.
+    pub returned: bool,
+    /// Time of the call, if it was recorded with a timestamp.
+    pub start: Option<i64>,
+    /// Time of the return, if it was recorded with a timestamp.
+    pub end: Option<i64>,
+    /// Calls made by this function, in order.
+    pub children: Vec<CallNode>,
+}
+
+impl CallNode {
+    /// Time spent in the call, including the calls it made.
+    pub fn inclusive_time(&self) -> Option<i64> {
+        match (self.start, self.end) {
+            (Some(start), Some(end)) => Some(end - start),
+            _ => None,
+        }
+    }
+
+    /// Time spent in the call, excluding the calls it made.
+    pub fn exclusive_time(&self) -> Option<i64> {
+        let children = self.children.iter().filter_map(CallNode::inclusive_time).sum::<i64>();
+        self.inclusive_time().map(|time| time - children)
+    }
+}
+
+impl Drop for CallNode {
+    /// Drops the calls under this one with an explicit stack, as `Walk` walks them.
+    fn drop(&mut self) {
+        let mut pending = mem::take(&mut self.children);
+        while let Some(mut node) = pending.pop() {
+            pending.append(&mut node.children);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// A step of a `Walk` over call trees.
+pub enum Visit<'a> {
+    /// Into a call, before its children.
+    Enter(&'a CallNode),
+    /// Out of a call, after its children.
+    Exit(&'a CallNode),
+}
+
+/// Walks call trees depth first, entering and exiting each call in the order the
+/// calls were made. The walk keeps its own stack, so that deep recursion in a trace
+/// cannot overflow the native one.
+pub struct Walk<'a> {
+    roots: slice::Iter<'a, CallNode>,
+    /// Calls entered and not yet exited, innermost last, each with the index of the
+    /// next child to enter.
+    stack: Vec<(&'a CallNode, usize)>,
+}
+
+impl<'a> Iterator for Walk<'a> {
+    type Item = Visit<'a>;
+
+    fn next(&mut self) -> Option<Visit<'a>> {
+        let node = match self.stack.last_mut() {
+            Some(&mut (node, ref mut next)) => {
+                match node.children.get(*next) {
+                    Some(child) => {
+                        *next += 1;
+                        child
+                    }
+                    None => {
+                        self.stack.pop();
+                        return Some(Visit::Exit(node));
+                    }
+                }
+            }
+            None => self.roots.next()?,
+        };
+        self.stack.push((node, 0));
+        Some(Visit::Enter(node))
+    }
+}
+
+/// Walks the trees under `roots`, in order.
+pub fn walk(roots: &[CallNode]) -> Walk<'_> {
+    Walk {
+        roots: roots.iter(),
+        stack: Vec::new(),
+    }
+}
+
+/// Nests `events` into trees of calls, returning the outermost calls in order.
+/// A return is matched with the innermost open call to the same function, and the
+/// calls still open inside it, whose returns were not logged, are closed without
+/// one. A return with no matching call, as found at the start of a truncated ring
+/// buffer, closes every open call and becomes the parent of everything before it.
+pub fn call_tree(events: &[TraceEvent]) -> Vec<CallNode> {
+    let mut roots = Vec::new();
+    // Calls that have not returned yet, innermost last.
+    let mut stack: Vec<CallNode> = Vec::new();
+
+    for event in events {
+        // Calls into imported functions are only seen from their call sites.
+        let (id, name, value, time) = match event {
+            TraceEvent::Call { id, name, args, time } => {
+                stack.push(CallNode {
+                               id: *id,
+                               name: name.clone(),
+                               args: args.clone(),
+                               value: None,
+                               entered: true,
+                               returned: false,
+                               start: *time,
+                               end: None,
+                               children: vec![],
+                           });
+                continue;
+            }
+            TraceEvent::CallSite {
+                site: CallSite { callee: Some(callee), callee_name, imported: true, .. },
+                time,
+                ..
+            } => {
+                stack.push(CallNode {
+                               id: *callee,
+                               name: callee_name.clone(),
+                               args: vec![],
+                               value: None,
+                               entered: true,
+                               returned: false,
+                               start: *time,
+                               end: None,
+                               children: vec![],
+                           });
+                continue;
+            }
+            TraceEvent::Return { id, name, value, time } => (*id, name, *value, time),
+            TraceEvent::CallSiteReturn {
+                site: CallSite { callee: Some(callee), callee_name, imported: true, .. },
+                time,
+                ..
+            } => (*callee, callee_name, None, time),
+            TraceEvent::CallSite { .. } |
+            TraceEvent::CallSiteReturn { .. } |
+            TraceEvent::IndirectCall { .. } => continue,
+        };
+
+        let matching = stack.iter().rposition(|node| node.id == id);
+        while stack.len() > matching.map_or(0, |depth| depth + 1) {
+            close_innermost(&mut stack, &mut roots);
+        }
+        let node = match matching {
+            Some(_) => {
+                let mut node = stack.pop().expect("Found the matching call");
+                node.value = value;
+                node.returned = true;
+                node.end = *time;
+                node
+            }
+            None => {
+                CallNode {
+                    id,
+                    name: name.clone(),
+                    args: vec![],
+                    value,
+                    entered: false,
+                    returned: true,
+                    start: None,
+                    end: *time,
+                    children: mem::take(&mut roots),
+                }
+            }
+        };
+        match stack.last_mut() {
+            Some(parent) => parent.children.push(node),
+            None => roots.push(node),
+        }
+    }
+
+    // Close calls that were still running when the trace ended.
+    while !stack.is_empty() {
+        close_innermost(&mut stack, &mut roots);
+    }
+    roots
+}
+
+/// Moves the innermost open call, which did not return, into its caller or `roots`.
+fn close_innermost(stack: &mut Vec<CallNode>, roots: &mut Vec<CallNode>) {
+    if let Some(node) = stack.pop() {
+        match stack.last_mut() {
+            Some(parent) => parent.children.push(node),
+            None => roots.push(node),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Time spent in one function, over every call in a trace.
+pub struct FunctionTime {
+    /// Index of the function in the function index space.
+    pub id: usize,
+    /// Name of the function, if known.
+    pub name: Option<String>,
+    /// Number of calls.
+    pub calls: u64,
+    /// Time from call to return. Recursive calls are only counted once, in the
+    /// outermost call.
+    pub inclusive: i64,
+    /// Time in the function itself, outside of the calls it made.
+    pub exclusive: i64,
+}
+
+/// Sums the time spent in each function over the call trees, slowest first by
+/// exclusive time. Calls without both timestamps count as calls, but take no time.
+pub fn function_times(roots: &[CallNode]) -> Vec<FunctionTime> {
+    let mut times = BTreeMap::new();
+    // How many calls to each function are entered and not yet exited.
+    let mut active: HashMap<usize, usize> = HashMap::new();
+    for visit in walk(roots) {
+        let node = match visit {
+            Visit::Enter(node) => node,
+            Visit::Exit(node) => {
+                *active.get_mut(&node.id).expect("Exited a call that was entered") -= 1;
+                continue;
+            }
+        };
+        let time = times.entry(node.id).or_insert_with(|| {
+            FunctionTime {
+                id: node.id,
+                name: node.name.clone(),
+                calls: 0,
+                inclusive: 0,
+                exclusive: 0,
+            }
+        });
+        time.calls += 1;
+        time.exclusive += node.exclusive_time().unwrap_or(0);
+        let depth = active.entry(node.id).or_insert(0);
+        if *depth == 0 {
+            time.inclusive += node.inclusive_time().unwrap_or(0);
+        }
+        *depth += 1;
+    }
+    let mut times = times.into_values().collect::<Vec<FunctionTime>>();
+    times.sort_by(|a, b| b.exclusive.cmp(&a.exclusive).then(a.id.cmp(&b.id)));
+    times
+}
+
+#[cfg(test)]
+mod test_trace {
+    use module::WasmModule;
+    use tracer::EntryKind;
+    use super::{call_tree, decode, function_times, CallNode, CallSite, FunctionTime, TraceEvent,
+                TraceValue};
+
+    fn entry(kind: EntryKind, id: i32, bits: i64) -> Vec<i32> {
+        vec![kind as i32, id, bits as i32, (bits >> 32) as i32]
+    }
+
+    fn call(id: usize) -> TraceEvent {
+        TraceEvent::Call { id, name: None, args: vec![], time: None }
+    }
+
+    fn ret(id: usize) -> TraceEvent {
+        TraceEvent::Return { id, name: None, value: None, time: None }
+    }
+
+    fn shape(nodes: &[CallNode]) -> String {
+        nodes.iter()
+            .map(|node| {
+                format!("{}{}{}[{}]",
+                        if node.entered { "" } else { "?" },
+                        node.id,
+                        if node.returned { "" } else { "!" },
+                        shape(&node.children))
+            })
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
+    #[test]
+    fn decode_entries() {
+        let module = WasmModule::from_file("./tests/function-names.wasm").unwrap();
+        let entries = [entry(EntryKind::ArgumentI32, 1, 9),
+                       entry(EntryKind::FunctionCall, 0, 0),
+                       entry(EntryKind::ArgumentI32, 0, -3),
+                       entry(EntryKind::ArgumentF64, 0, 2.5f64.to_bits() as i64),
+                       vec![42, 0, 0, 0],
+                       entry(EntryKind::FunctionReturnI64, 0, -1 << 40),
+                       entry(EntryKind::FunctionReturnVoid, 7, 0),
+                       vec![EntryKind::FunctionCall as i32, 2]]
+            .concat();
+
+        assert_eq!(decode(&entries, &module),
+                   vec![TraceEvent::Call {
+                            id: 0,
+                            name: Some("_Z3addii".to_owned()),
+                            args: vec![TraceValue::I32(-3), TraceValue::F64(2.5)],
+                            time: None,
+                        },
+                        TraceEvent::Return {
+                            id: 0,
+                            name: Some("_Z3addii".to_owned()),
+                            value: Some(TraceValue::I64(-1 << 40)),
+                            time: None,
+                        },
+                        TraceEvent::Return { id: 7, name: None, value: None, time: None }]);
+    }
+
+    #[test]
+    fn nest_calls() {
+        let events = [call(0), call(1), ret(1), call(2), call(3), ret(3), ret(2), ret(0), call(4),
+                      ret(4)];
+        assert_eq!(shape(&call_tree(&events)), "0[1[] 2[3[]]] 4[]");
+    }
+
+    #[test]
+    fn truncated_prefix() {
+        // The calls to 1 and 0 were overwritten before the buffer was read.
+        let events = [call(2), ret(2), ret(1), call(3), ret(3), ret(0)];
+        assert_eq!(shape(&call_tree(&events)), "?0[?1[2[]] 3[]]");
+    }
+
+    #[test]
+    fn unfinished_calls() {
+        let events = [call(0), call(1), ret(1), call(2)];
+        assert_eq!(shape(&call_tree(&events)), "0![1[] 2![]]");
+    }
+
+    #[test]
+    fn mismatched_returns() {
+        // The returns of 2 and 1 were not logged.
+        let events = [call(0), call(1), call(2), ret(0), call(3), ret(3)];
+        assert_eq!(shape(&call_tree(&events)), "0[1![2![]]] 3[]");
+
+        // A return that matches no open call closes all of them.
+        let events = [call(0), call(1), ret(5), call(3), ret(3)];
+        assert_eq!(shape(&call_tree(&events)), "?5[0![1![]]] 3[]");
+    }
+
+    #[test]
+    fn decode_timestamps() {
+        let module = WasmModule::from_file("./tests/function-names.wasm").unwrap();
+        let entries = [entry(EntryKind::FunctionCall, 0, 0),
+                       entry(EntryKind::ArgumentI32, 0, 1),
+                       entry(EntryKind::Timestamp, 0, 100),
+                       entry(EntryKind::FunctionReturnVoid, 0, 0),
+                       entry(EntryKind::Timestamp, 0, 1 << 40),
+                       // A timestamp without an event for the same function is dropped.
+                       entry(EntryKind::Timestamp, 3, 7)]
+            .concat();
+
+        let name = Some("_Z3addii".to_owned());
+        assert_eq!(decode(&entries, &module),
+                   vec![TraceEvent::Call {
+                            id: 0,
+                            name: name.clone(),
+                            args: vec![TraceValue::I32(1)],
+                            time: Some(100),
+                        },
+                        TraceEvent::Return { id: 0, name, value: None, time: Some(1 << 40) }]);
+    }
+
+    #[test]
+    fn decode_call_sites() {
+        let module = WasmModule::from_file("./tests/imports.wasm").unwrap();
+        let caller = module.imported_functions_count();
+        let entries = [entry(EntryKind::FunctionCall, caller as i32, 0),
+                       entry(EntryKind::CallSite, caller as i32, 2),
+                       entry(EntryKind::CallSiteReturn, caller as i32, 2),
+                       entry(EntryKind::CallSite, caller as i32, (-1 << 32) | 5),
+                       entry(EntryKind::CallSiteReturn, caller as i32, (-1 << 32) | 5),
+                       entry(EntryKind::FunctionReturnVoid, caller as i32, 0)]
+            .concat();
+        let events = decode(&entries, &module);
+
+        let site = |event: &TraceEvent| match event {
+            TraceEvent::CallSite { site, .. } => Some(site.clone()),
+            _ => None,
+        };
+        assert_eq!(site(&events[1]),
+                   Some(CallSite {
+                            offset: 2,
+                            callee: Some(0),
+                            callee_name: Some("printf".to_owned()),
+                            imported: true,
+                        }));
+        assert_eq!(site(&events[3]),
+                   Some(CallSite { offset: 5, callee: None, callee_name: None, imported: false }));
+
+        // Only calls to imported functions become nodes of their own.
+        let roots = call_tree(&events);
+        assert_eq!(shape(&roots), format!("{}[0[]]", caller));
+    }
+
+    #[test]
+    fn sum_function_times() {
+        let timed_call = |id: usize, time: i64| {
+            TraceEvent::Call { id, name: None, args: vec![], time: Some(time) }
+        };
+        let timed_ret = |id: usize, time: i64| {
+            TraceEvent::Return { id, name: None, value: None, time: Some(time) }
+        };
+        // 0 calls 1, which recurses once, then 2.
+        let events = [timed_call(0, 0),
+                      timed_call(1, 10),
+                      timed_call(1, 20),
+                      timed_ret(1, 30),
+                      timed_ret(1, 50),
+                      timed_call(2, 60),
+                      timed_ret(2, 90),
+                      timed_ret(0, 100)];
+        let roots = call_tree(&events);
+        assert_eq!(roots[0].inclusive_time(), Some(100));
+        assert_eq!(roots[0].exclusive_time(), Some(30));
+
+        let time = |id: usize, calls: u64, inclusive: i64, exclusive: i64| {
+            FunctionTime { id, name: None, calls, inclusive, exclusive }
+        };
+        assert_eq!(function_times(&roots),
+                   vec![time(1, 2, 40, 40), time(0, 1, 100, 30), time(2, 1, 30, 30)]);
+
+        // Calls without timestamps are counted, but take no time.
+        let events = [timed_call(0, 0), timed_ret(0, 5), call(0)];
+        assert_eq!(function_times(&call_tree(&events)), vec![time(0, 2, 5, 5)]);
+    }
+}
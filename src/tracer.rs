@@ -1,53 +1,327 @@
 //! Function call and return tracing capabilities.
 
+use core::sync::atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering};
+#[cfg(feature = "alloc")]
 use ring_buffer::RingBuffer;
 
 pub static LOG_CALL: &str = "__log_call";
 pub static EXPOSE_TRACER: &str = "__expose_tracer";
 pub static EXPOSE_TRACER_LEN: &str = "__expose_tracer_len";
+pub static EXPOSE_TRACER_CAPACITY: &str = "__expose_tracer_capacity";
+pub static EXPOSE_TRACER_HEAD: &str = "__expose_tracer_head";
+pub static EXPOSE_TRACER_WRAPPED: &str = "__expose_tracer_wrapped";
 
-static TRACER_LOG_ENTRIES: usize = 1024;
+/// Exports that let the host trace only part of a run. `__tracer_disable` makes
+/// `__log_call` return without logging until `__tracer_enable` is called, and
+/// `__tracer_clear` drops every entry logged so far.
+pub static TRACER_ENABLE: &str = "__tracer_enable";
+pub static TRACER_DISABLE: &str = "__tracer_disable";
+pub static TRACER_CLEAR: &str = "__tracer_clear";
+
+/// Module and field of the host function that receives entries live,
+/// with the same signature as `__log_call`.
+pub static LOG_IMPORT_MODULE: &str = "wasm_trace";
+pub static LOG_IMPORT: &str = "log";
+
+/// Field of the host function that returns the current time as an `i64`, in the
+/// same module as `LOG_IMPORT`.
+pub static CLOCK_IMPORT: &str = "now";
+
+/// Number of entries a tracer keeps unless told otherwise.
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+/// Number of `i32` words per log entry: the kind, the function index, and the
+/// low and high halves of the value bits.
+pub const ENTRY_WORDS: usize = 4;
 
 #[repr(i32)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 /// Characterizes the kind of the logged data.
+/// Return and argument kinds record the type of the value, so that its bits can be
+/// decoded faithfully. Argument entries directly follow the call they belong to.
+/// A timestamp entry, if any, follows the call (and its arguments) or return it times.
+/// Call site entries are logged by the caller around a call instruction. Their
+/// value holds the offset of the instruction in the caller's original body in the
+/// low half, and the callee in the high half, or -1 for `call_indirect`.
+/// An indirect call entry is logged by the caller just before a `call_indirect`,
+/// with the instruction's offset in the low half and the table index in the high half.
 pub enum EntryKind {
     FunctionCall = 0,
     FunctionReturnVoid = 1,
-    FunctionReturnValue = 2,
+    FunctionReturnI32 = 2,
+    FunctionReturnI64 = 3,
+    FunctionReturnF32 = 4,
+    FunctionReturnF64 = 5,
+    ArgumentI32 = 6,
+    ArgumentI64 = 7,
+    ArgumentF32 = 8,
+    ArgumentF64 = 9,
+    Timestamp = 10,
+    CallSite = 11,
+    CallSiteReturn = 12,
+    IndirectCall = 13,
+}
+
+impl EntryKind {
+    /// Looks up the kind recorded in the first word of a log entry.
+    pub fn from_i32(kind: i32) -> Option<EntryKind> {
+        let kind = match kind {
+            0 => EntryKind::FunctionCall,
+            1 => EntryKind::FunctionReturnVoid,
+            2 => EntryKind::FunctionReturnI32,
+            3 => EntryKind::FunctionReturnI64,
+            4 => EntryKind::FunctionReturnF32,
+            5 => EntryKind::FunctionReturnF64,
+            6 => EntryKind::ArgumentI32,
+            7 => EntryKind::ArgumentI64,
+            8 => EntryKind::ArgumentF32,
+            9 => EntryKind::ArgumentF64,
+            10 => EntryKind::Timestamp,
+            11 => EntryKind::CallSite,
+            12 => EntryKind::CallSiteReturn,
+            13 => EntryKind::IndirectCall,
+            _ => return None,
+        };
+        Some(kind)
+    }
+
+    /// Whether this entry records an argument of the preceding call.
+    pub fn is_argument(self) -> bool {
+        matches!(self,
+                 EntryKind::ArgumentI32 | EntryKind::ArgumentI64 | EntryKind::ArgumentF32 |
+                 EntryKind::ArgumentF64)
+    }
 }
 
+/// Whether `name` is one of the functions that a tracer exports.
+pub fn is_tracer_export(name: &str) -> bool {
+    [LOG_CALL,
+     EXPOSE_TRACER,
+     EXPOSE_TRACER_LEN,
+     EXPOSE_TRACER_CAPACITY,
+     EXPOSE_TRACER_HEAD,
+     EXPOSE_TRACER_WRAPPED,
+     TRACER_ENABLE,
+     TRACER_DISABLE,
+     TRACER_CLEAR]
+        .contains(&name)
+}
+
+#[cfg(feature = "alloc")]
 /// Wrapper around the ring buffer for recording function calls.
+/// Lengths, capacities and indices are in `i32` words, `ENTRY_WORDS` per entry.
+/// Entries are never moved once logged: the buffer at `as_ptr` holds `len` words,
+/// oldest first, unless it has wrapped, in which case the oldest word is at
+/// `head` and the words before it are the newest.
+/// A new tracer is enabled.
 #[derive(Debug)]
-pub struct Tracer(RingBuffer<i32>);
+pub struct Tracer {
+    buffer: RingBuffer<i32>,
+    enabled: bool,
+}
 
+#[cfg(feature = "alloc")]
 impl Tracer {
-    /// Creates a `Tracer` initalized to capture 1024 log events.
+    /// Creates a `Tracer` initalized to capture `DEFAULT_CAPACITY` log events.
     pub fn new() -> Self {
-        Tracer(RingBuffer::new(TRACER_LOG_ENTRIES * 2))
+        Tracer::with_capacity(DEFAULT_CAPACITY)
+    }
+
+    /// Creates a `Tracer` that keeps the last `entries` log events.
+    pub fn with_capacity(entries: usize) -> Self {
+        Tracer {
+            buffer: RingBuffer::new(entries * ENTRY_WORDS),
+            enabled: true,
+        }
+    }
+
+    /// Records the kind, the index of the function involved, and any associated
+    /// value (e.g. a returned `f64`) as raw bits, in the buffer, unless the
+    /// tracer is disabled.
+    pub fn log(&mut self, kind: i32, id: i32, bits: i64) {
+        if !self.enabled {
+            return;
+        }
+        self.buffer.enqueue(kind);
+        self.buffer.enqueue(id);
+        self.buffer.enqueue(bits as i32);
+        self.buffer.enqueue((bits >> 32) as i32);
     }
 
-    /// Records the kind and associated data (e.g. called function index)
-    /// in the buffer.
-    pub fn log(&mut self, kind: i32, data: i32) {
-        self.0.enqueue(kind as i32);
-        self.0.enqueue(data);
+    /// Resumes logging.
+    pub fn enable(&mut self) {
+        self.enabled = true;
+    }
+
+    /// Stops logging until `enable` is called.
+    pub fn disable(&mut self) {
+        self.enabled = false;
+    }
+
+    /// Returns whether entries are being logged.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Drops every entry logged so far.
+    pub fn clear(&mut self) {
+        self.buffer.clear();
     }
 
     /// Converts to a raw pointer.
     pub fn as_ptr(&self) -> *const i32 {
-        self.0.as_slice().as_ptr()
+        self.buffer.as_slice().as_ptr()
     }
 
     /// Returns the length of the buffer.
     pub fn len(&self) -> usize {
-        self.0.len()
+        self.buffer.len()
+    }
+
+    /// Returns the length of the buffer once it is full.
+    pub fn capacity(&self) -> usize {
+        self.buffer.capacity()
+    }
+
+    /// Returns the index of the word the next entry is written to.
+    pub fn head(&self) -> usize {
+        self.buffer.head()
+    }
+
+    /// Returns whether older entries have been overwritten.
+    pub fn is_wrapped(&self) -> bool {
+        self.buffer.is_wrapped()
+    }
+
+    /// Returns whether nothing has been logged yet.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl Default for Tracer {
+    fn default() -> Self {
+        Tracer::new()
+    }
+}
+
+/// Tracer that keeps the last `N` entries in a fixed array, with the same layout
+/// as `Tracer`, but that can be built in a `static` and logged to without a lock
+/// or a lazy initialization check.
+/// Each log reserves a slot with a single atomic update, so entries logged
+/// concurrently do not overwrite each other until the ring wraps around to them.
+pub struct StaticTracer<const N: usize> {
+    entries: [[AtomicI32; ENTRY_WORDS]; N],
+    /// Slot the next entry is written to, up to `N`.
+    head: AtomicUsize,
+    /// Whether an entry has been overwritten.
+    wrapped: AtomicBool,
+    enabled: AtomicBool,
+}
+
+impl<const N: usize> StaticTracer<N> {
+    /// Creates an empty `StaticTracer`.
+    pub const fn new() -> Self {
+        StaticTracer {
+            entries: [const { [const { AtomicI32::new(0) }; ENTRY_WORDS] }; N],
+            head: AtomicUsize::new(0),
+            wrapped: AtomicBool::new(false),
+            enabled: AtomicBool::new(true),
+        }
+    }
+
+    /// Records the kind, the index of the function involved, and any associated
+    /// value as raw bits, like `Tracer::log`.
+    pub fn log(&self, kind: i32, id: i32, bits: i64) {
+        if N == 0 || !self.enabled.load(Ordering::Relaxed) {
+            return;
+        }
+        let head = self.head
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |head| {
+                Some(if head == N { 1 } else { head + 1 })
+            })
+            .unwrap_or_else(|head| head);
+        let slot = if head == N {
+            self.wrapped.store(true, Ordering::Relaxed);
+            0
+        } else {
+            head
+        };
+
+        let entry = &self.entries[slot];
+        for (word, value) in entry.iter().zip([kind, id, bits as i32, (bits >> 32) as i32]) {
+            word.store(value, Ordering::Relaxed);
+        }
+    }
+
+    /// Resumes logging.
+    pub fn enable(&self) {
+        self.enabled.store(true, Ordering::Relaxed);
+    }
+
+    /// Stops logging until `enable` is called.
+    pub fn disable(&self) {
+        self.enabled.store(false, Ordering::Relaxed);
+    }
+
+    /// Returns whether entries are being logged.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Drops every entry logged so far.
+    pub fn clear(&self) {
+        self.head.store(0, Ordering::Relaxed);
+        self.wrapped.store(false, Ordering::Relaxed);
+    }
+
+    /// Converts to a raw pointer to the first word.
+    pub fn as_ptr(&self) -> *const i32 {
+        // `AtomicI32` has the same in-memory representation as `i32`.
+        self.entries.as_ptr() as *const i32
+    }
+
+    /// Returns the number of words in use.
+    pub fn len(&self) -> usize {
+        if self.is_wrapped() {
+            self.capacity()
+        } else {
+            self.head()
+        }
+    }
+
+    /// Returns the number of words in the array.
+    pub fn capacity(&self) -> usize {
+        N * ENTRY_WORDS
+    }
+
+    /// Returns the index of the word the next entry is written to.
+    pub fn head(&self) -> usize {
+        self.head.load(Ordering::Relaxed) * ENTRY_WORDS
+    }
+
+    /// Returns whether older entries have been overwritten.
+    pub fn is_wrapped(&self) -> bool {
+        self.wrapped.load(Ordering::Relaxed)
+    }
+
+    /// Returns whether nothing has been logged yet.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<const N: usize> Default for StaticTracer<N> {
+    fn default() -> Self {
+        StaticTracer::new()
     }
 }
 
 #[macro_export]
 /// Provides dependencies for the Tracer.
 /// Designed to be imported into a user's module at the root level.
+/// Needs `std` and `lazy_static`; `no_std` guests use `tracer_bootstrap_static!`.
 macro_rules! tracer_dependencies {
     () => {
         #[macro_use] extern crate lazy_static;
@@ -58,64 +332,237 @@ macro_rules! tracer_dependencies {
 #[macro_export]
 /// Bootstraps a Tracer into the module root, allowing our reinstrumentation to
 /// write to the ring buffer.
+/// Takes the number of entries to keep, `DEFAULT_CAPACITY` if omitted.
 macro_rules! tracer_bootstrap {
     () => {
+        tracer_bootstrap!($crate::tracer::DEFAULT_CAPACITY);
+    };
+    ($capacity:expr) => {
         lazy_static! {
-            static ref TRACER: Mutex<Tracer> = Mutex::new(Tracer::new());
+            static ref TRACER: Mutex<Tracer> = Mutex::new(Tracer::with_capacity($capacity));
         }
 
-        #[allow(private_no_mangle_fns)]
         #[no_mangle]
-        pub fn __log_call(id: i32, data: i32) {
-            TRACER.lock().unwrap().log(id, data);
+        pub fn __log_call(kind: i32, id: i32, bits: i64) {
+            TRACER.lock().unwrap().log(kind, id, bits);
         }
 
-        #[allow(private_no_mangle_fns)]
         #[no_mangle]
         pub fn __expose_tracer() -> *const i32 {
             TRACER.lock().unwrap().as_ptr()
         }
 
-        #[allow(private_no_mangle_fns)]
         #[no_mangle]
         pub fn __expose_tracer_len() -> u32 {
             TRACER.lock().unwrap().len() as u32
         }
+
+        #[no_mangle]
+        pub fn __expose_tracer_capacity() -> u32 {
+            TRACER.lock().unwrap().capacity() as u32
+        }
+
+        #[no_mangle]
+        pub fn __expose_tracer_head() -> u32 {
+            TRACER.lock().unwrap().head() as u32
+        }
+
+        #[no_mangle]
+        pub fn __expose_tracer_wrapped() -> u32 {
+            TRACER.lock().unwrap().is_wrapped() as u32
+        }
+
+        #[no_mangle]
+        pub fn __tracer_enable() {
+            TRACER.lock().unwrap().enable();
+        }
+
+        #[no_mangle]
+        pub fn __tracer_disable() {
+            TRACER.lock().unwrap().disable();
+        }
+
+        #[no_mangle]
+        pub fn __tracer_clear() {
+            TRACER.lock().unwrap().clear();
+        }
     }
 }
 
-#[cfg(test)]
+#[macro_export]
+/// Bootstraps a `StaticTracer` into the module root, exporting the same functions
+/// as `tracer_bootstrap!`. Logging takes neither a lock nor a lazy initialization
+/// check, and no other crate is needed, so it also works in `#![no_std]` guests.
+/// Takes the number of entries to keep, as a constant, `DEFAULT_CAPACITY` if
+/// omitted.
+macro_rules! tracer_bootstrap_static {
+    () => {
+        tracer_bootstrap_static!($crate::tracer::DEFAULT_CAPACITY);
+    };
+    ($capacity:expr) => {
+        static TRACER: $crate::tracer::StaticTracer<{ $capacity }> =
+            $crate::tracer::StaticTracer::new();
+
+        #[no_mangle]
+        pub fn __log_call(kind: i32, id: i32, bits: i64) {
+            TRACER.log(kind, id, bits);
+        }
+
+        #[no_mangle]
+        pub fn __expose_tracer() -> *const i32 {
+            TRACER.as_ptr()
+        }
+
+        #[no_mangle]
+        pub fn __expose_tracer_len() -> u32 {
+            TRACER.len() as u32
+        }
+
+        #[no_mangle]
+        pub fn __expose_tracer_capacity() -> u32 {
+            TRACER.capacity() as u32
+        }
+
+        #[no_mangle]
+        pub fn __expose_tracer_head() -> u32 {
+            TRACER.head() as u32
+        }
+
+        #[no_mangle]
+        pub fn __expose_tracer_wrapped() -> u32 {
+            TRACER.is_wrapped() as u32
+        }
+
+        #[no_mangle]
+        pub fn __tracer_enable() {
+            TRACER.enable();
+        }
+
+        #[no_mangle]
+        pub fn __tracer_disable() {
+            TRACER.disable();
+        }
+
+        #[no_mangle]
+        pub fn __tracer_clear() {
+            TRACER.clear();
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+#[allow(clippy::ptr_offset_with_cast)]
 mod test_tracer {
-    use itertools::Itertools;
-    use super::{Tracer, EntryKind};
+    use super::{StaticTracer, Tracer, EntryKind, DEFAULT_CAPACITY, ENTRY_WORDS};
 
     #[test]
     fn get_ptr() {
         let mut tracer = Tracer::new();
-        let kinds = vec![EntryKind::FunctionCall as i32,
-                         EntryKind::FunctionCall as i32,
-                         EntryKind::FunctionCall as i32];
-        let values = vec![4, 1, 2];
-        for (&kind, &x) in kinds.clone().iter().zip(values.clone().iter()) {
-            tracer.log(kind, x);
+        let entries = [(EntryKind::FunctionCall as i32, 4, 0),
+                       (EntryKind::FunctionReturnI64 as i32, 4, -2),
+                       (EntryKind::FunctionReturnF64 as i32, 1, 2.5f64.to_bits() as i64)];
+        for &(kind, id, bits) in entries.iter() {
+            tracer.log(kind, id, bits);
         }
 
         let ptr = tracer.as_ptr();
         let len = tracer.len();
-        let mut expected_values = kinds.iter().interleave(values.iter());
+        assert_eq!(len, entries.len() * ENTRY_WORDS);
+        let expected_values = entries
+            .iter()
+            .flat_map(|&(kind, id, bits)| vec![kind, id, bits as i32, (bits >> 32) as i32]);
 
         unsafe {
-            for i in 0..len {
-                let &expected = expected_values.next().unwrap();
+            for (i, expected) in expected_values.enumerate() {
                 assert_eq!(*ptr.offset(i as isize), expected);
             }
         }
     }
 
+    #[test]
+    fn keep_last_entries() {
+        let mut tracer = Tracer::with_capacity(2);
+        for id in 0..2 {
+            tracer.log(EntryKind::FunctionCall as i32, id, 0);
+        }
+        assert!(!tracer.is_wrapped());
+
+        // The third entry overwrites the first, and the second is now the oldest.
+        tracer.log(EntryKind::FunctionCall as i32, 2, 0);
+        assert_eq!(tracer.len(), tracer.capacity());
+        assert_eq!(tracer.capacity(), 2 * ENTRY_WORDS);
+        assert!(tracer.is_wrapped());
+        assert_eq!(tracer.head(), ENTRY_WORDS);
+        unsafe {
+            assert_eq!(*tracer.as_ptr().add(1), 2);
+            assert_eq!(*tracer.as_ptr().add(ENTRY_WORDS + 1), 1);
+        }
+    }
+
+    #[test]
+    fn disable_and_clear() {
+        let mut tracer = Tracer::with_capacity(2);
+        tracer.log(EntryKind::FunctionCall as i32, 0, 0);
+        tracer.disable();
+        assert!(!tracer.is_enabled());
+        tracer.log(EntryKind::FunctionCall as i32, 1, 0);
+        assert_eq!(tracer.len(), ENTRY_WORDS);
+
+        tracer.enable();
+        tracer.log(EntryKind::FunctionCall as i32, 2, 0);
+        tracer.log(EntryKind::FunctionCall as i32, 3, 0);
+        assert!(tracer.is_wrapped());
+
+        tracer.clear();
+        assert!(tracer.is_empty());
+        assert!(!tracer.is_wrapped());
+        tracer.log(EntryKind::FunctionCall as i32, 4, 0);
+        assert_eq!(tracer.len(), ENTRY_WORDS);
+        unsafe {
+            assert_eq!(*tracer.as_ptr().add(1), 4);
+        }
+    }
+
+    #[test]
+    fn static_tracer_matches_tracer() {
+        static STATIC: StaticTracer<3> = StaticTracer::new();
+        let mut tracer = Tracer::with_capacity(3);
+        assert!(STATIC.is_empty());
+
+        let words = |ptr: *const i32, len: usize| unsafe {
+            (0..len).map(|i| *ptr.add(i)).collect::<Vec<i32>>()
+        };
+        for id in 0..12 {
+            // Clear both tracers halfway through, and skip a few entries.
+            if id == 6 {
+                STATIC.clear();
+                tracer.clear();
+            }
+            if id == 8 {
+                STATIC.disable();
+                tracer.disable();
+            }
+            if id == 10 {
+                STATIC.enable();
+                tracer.enable();
+            }
+            STATIC.log(EntryKind::FunctionReturnI64 as i32, id, -(id as i64));
+            tracer.log(EntryKind::FunctionReturnI64 as i32, id, -(id as i64));
+            assert_eq!((STATIC.len(), STATIC.head(), STATIC.is_wrapped()),
+                       (tracer.len(), tracer.head(), tracer.is_wrapped()));
+            assert_eq!(words(STATIC.as_ptr(), STATIC.len()),
+                       words(tracer.as_ptr(), tracer.len()));
+        }
+        assert_eq!(STATIC.capacity(), tracer.capacity());
+    }
+
     #[test]
     fn bootstrap() {
         use std::sync::Mutex;
         tracer_bootstrap!();
         assert_eq!(__expose_tracer_len(), 0);
+        assert_eq!(__expose_tracer_capacity() as usize, DEFAULT_CAPACITY * ENTRY_WORDS);
+        assert_eq!(__expose_tracer_head(), 0);
+        assert_eq!(__expose_tracer_wrapped(), 0);
     }
 }
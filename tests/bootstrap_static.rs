@@ -0,0 +1,35 @@
+//! `tracer_bootstrap_static!` defines `#[no_mangle]` functions, so it cannot be
+//! expanded next to `tracer_bootstrap!` in the library's own tests.
+
+#[macro_use]
+extern crate wasm_trace;
+
+use wasm_trace::tracer::{EntryKind, ENTRY_WORDS};
+
+tracer_bootstrap_static!(2);
+
+#[test]
+fn bootstrap_static() {
+    assert_eq!(__expose_tracer_len(), 0);
+    assert_eq!(__expose_tracer_capacity() as usize, 2 * ENTRY_WORDS);
+
+    for id in 0..3 {
+        __log_call(EntryKind::FunctionCall as i32, id, 0);
+    }
+    assert_eq!(__expose_tracer_len() as usize, 2 * ENTRY_WORDS);
+    assert_eq!(__expose_tracer_head() as usize, ENTRY_WORDS);
+    assert_eq!(__expose_tracer_wrapped(), 1);
+    // The third entry took the place of the first.
+    unsafe {
+        assert_eq!(*__expose_tracer().add(1), 2);
+    }
+
+    __tracer_clear();
+    __tracer_disable();
+    __log_call(EntryKind::FunctionCall as i32, 3, 0);
+    assert_eq!(__expose_tracer_len(), 0);
+    assert_eq!(__expose_tracer_wrapped(), 0);
+    __tracer_enable();
+    __log_call(EntryKind::FunctionCall as i32, 4, 0);
+    assert_eq!(__expose_tracer_len() as usize, ENTRY_WORDS);
+}